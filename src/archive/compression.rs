@@ -0,0 +1,243 @@
+use failure::Error;
+use flate2::read::{DeflateDecoder, GzDecoder};
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::{write, Compression};
+use hyper::header::{self, HeaderMap};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Gzip,
+    Deflate,
+    Brotli,
+}
+
+impl ContentEncoding {
+    pub fn from_name(name: &str) -> Option<ContentEncoding> {
+        match name.trim().to_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(ContentEncoding::Gzip),
+            "deflate" => Some(ContentEncoding::Deflate),
+            "br" => Some(ContentEncoding::Brotli),
+            _ => None,
+        }
+    }
+
+    /// Only a single, known coding is handled; stacked codings
+    /// (e.g. `gzip, br`) are left alone and stored as-is.
+    pub fn from_headers(headers: &HeaderMap) -> Option<ContentEncoding> {
+        let mut values = headers.get_all(header::CONTENT_ENCODING).iter();
+        let value = values.next()?.to_str().ok()?;
+        if values.next().is_some() || value.contains(',') {
+            return None;
+        }
+        ContentEncoding::from_name(value)
+    }
+
+    pub fn decode(self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::new();
+        self.decoder(data).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    /// Reads `encoded` decoded
+    pub fn decoder<'a, R: Read + 'a>(self, encoded: R) -> Box<dyn Read + 'a> {
+        match self {
+            ContentEncoding::Gzip => Box::new(GzDecoder::new(encoded)),
+            ContentEncoding::Deflate => Box::new(DeflateDecoder::new(encoded)),
+            ContentEncoding::Brotli => Box::new(brotli::Decompressor::new(encoded, 4096)),
+        }
+    }
+
+    pub fn encode(self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            ContentEncoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+            ContentEncoding::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+            ContentEncoding::Brotli => {
+                let mut out = Vec::new();
+                {
+                    let mut encoder = brotli::CompressorWriter::new(&mut out, 4096, 9, 22);
+                    encoder.write_all(data)?;
+                }
+                Ok(out)
+            }
+        }
+    }
+
+    /// Whether the `Accept-Encoding` header(s) in `headers` allow this coding.
+    /// A coding is acceptable if it (or `*`) is listed without `q=0`.
+    pub fn accepted_by(self, headers: &HeaderMap) -> bool {
+        let mut wildcard = false;
+        for value in headers.get_all(header::ACCEPT_ENCODING).iter() {
+            let value = match value.to_str() {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            for item in value.split(',') {
+                let mut parts = item.split(';');
+                let coding = parts.next().unwrap_or("").trim().to_lowercase();
+                let rejected = parts.any(|p| {
+                    let p = p.trim();
+                    p.starts_with("q=") && p[2..].parse::<f32>().map(|q| q == 0.0).unwrap_or(false)
+                });
+                if coding == "*" {
+                    wildcard = !rejected;
+                } else if ContentEncoding::from_name(&coding) == Some(self) {
+                    return !rejected;
+                }
+            }
+        }
+        wildcard
+    }
+}
+
+/// Where a streaming decoder or encoder leaves its output
+#[derive(Clone, Default)]
+struct Sink(Arc<Mutex<Vec<u8>>>);
+
+impl Sink {
+    fn take(&self) -> Vec<u8> {
+        mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Decodes a body a chunk at a time and encodes it again, so that it can be
+/// changed in between without waiting for the whole of it
+pub struct Recoder {
+    decoder: Option<Box<dyn Write + Send>>,
+    encoder: Option<Box<dyn Write + Send>>,
+    decoded: Sink,
+    encoded: Sink,
+}
+
+impl Recoder {
+    pub fn new(encoding: ContentEncoding) -> Recoder {
+        let decoded = Sink::default();
+        let encoded = Sink::default();
+        let (decoder, encoder): (Box<dyn Write + Send>, Box<dyn Write + Send>) = match encoding {
+            ContentEncoding::Gzip => (
+                Box::new(write::GzDecoder::new(decoded.clone())),
+                Box::new(GzEncoder::new(encoded.clone(), Compression::default())),
+            ),
+            ContentEncoding::Deflate => (
+                Box::new(write::DeflateDecoder::new(decoded.clone())),
+                Box::new(DeflateEncoder::new(encoded.clone(), Compression::default())),
+            ),
+            ContentEncoding::Brotli => (
+                Box::new(brotli::DecompressorWriter::new(decoded.clone(), 4096)),
+                Box::new(brotli::CompressorWriter::new(encoded.clone(), 4096, 9, 22)),
+            ),
+        };
+        Recoder {
+            decoder: Some(decoder),
+            encoder: Some(encoder),
+            decoded,
+            encoded,
+        }
+    }
+
+    /// Decode `chunk`, change what it decodes to with `f` and encode the result.
+    /// Everything encoded so far is flushed, so the body keeps streaming.
+    pub fn push<F>(&mut self, chunk: &[u8], f: F) -> io::Result<Vec<u8>>
+    where
+        F: FnOnce(&[u8]) -> Vec<u8>,
+    {
+        let decoder = self.decoder.as_mut().ok_or_else(finished)?;
+        decoder.write_all(chunk)?;
+        decoder.flush()?;
+        let changed = f(&self.decoded.take());
+        let encoder = self.encoder.as_mut().ok_or_else(finished)?;
+        encoder.write_all(&changed)?;
+        encoder.flush()?;
+        Ok(self.encoded.take())
+    }
+
+    /// The end of the body, with whatever `f` held back added before it's encoded
+    pub fn finish<F>(&mut self, f: F) -> io::Result<Vec<u8>>
+    where
+        F: FnOnce(&[u8]) -> Vec<u8>,
+    {
+        // Both finish their streams when dropped
+        self.decoder.take().ok_or_else(finished)?;
+        let changed = f(&self.decoded.take());
+        let mut encoder = self.encoder.take().ok_or_else(finished)?;
+        encoder.write_all(&changed)?;
+        drop(encoder);
+        Ok(self.encoded.take())
+    }
+}
+
+fn finished() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "body already finished")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ContentEncoding, Recoder};
+    use hyper::header::{self, HeaderMap, HeaderValue};
+
+    #[test]
+    fn test_round_trip() {
+        let data = b"some text that will be compressed, some text that will be compressed";
+        for enc in &[
+            ContentEncoding::Gzip,
+            ContentEncoding::Deflate,
+            ContentEncoding::Brotli,
+        ] {
+            let encoded = enc.encode(data).unwrap();
+            assert_eq!(&data[..], &enc.decode(&encoded).unwrap()[..]);
+        }
+    }
+
+    #[test]
+    fn test_recoder() {
+        let data = b"some text that will be compressed, some text that will be compressed";
+        for enc in &[
+            ContentEncoding::Gzip,
+            ContentEncoding::Deflate,
+            ContentEncoding::Brotli,
+        ] {
+            let encoded = enc.encode(data).unwrap();
+            let mut recoder = Recoder::new(*enc);
+            let mut out = Vec::new();
+            for chunk in encoded.chunks(7) {
+                out.extend(recoder.push(chunk, |d| d.to_ascii_uppercase()).unwrap());
+            }
+            out.extend(recoder.finish(|d| d.to_ascii_uppercase()).unwrap());
+            assert_eq!(data.to_ascii_uppercase(), enc.decode(&out).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_accepted_by() {
+        let mut headers = HeaderMap::new();
+        assert!(!ContentEncoding::Gzip.accepted_by(&headers));
+        headers.insert(
+            header::ACCEPT_ENCODING,
+            HeaderValue::from_static("gzip, deflate;q=0, *;q=0.1"),
+        );
+        assert!(ContentEncoding::Gzip.accepted_by(&headers));
+        assert!(!ContentEncoding::Deflate.accepted_by(&headers));
+        assert!(ContentEncoding::Brotli.accepted_by(&headers));
+    }
+}
@@ -0,0 +1,136 @@
+use super::dates;
+use super::ReplayBody;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::http::request::Parts as RequestParts;
+use hyper::{Body, Method, Response, StatusCode};
+
+/// Headers a 304 keeps from the response it stands in for
+const KEPT: &[header::HeaderName] = &[
+    header::CACHE_CONTROL,
+    header::CONTENT_LOCATION,
+    header::DATE,
+    header::ETAG,
+    header::EXPIRES,
+    header::VARY,
+];
+
+/// Opaque part of an entity tag, for weak comparison
+fn opaque(tag: &str) -> &str {
+    let tag = tag.trim();
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+fn matches_etag(if_none_match: &HeaderValue, etag: Option<&HeaderValue>) -> bool {
+    let if_none_match = match if_none_match.to_str() {
+        Ok(v) => v,
+        Err(_) => return false,
+    };
+    if if_none_match.trim() == "*" {
+        return true;
+    }
+    match etag.and_then(|e| e.to_str().ok()) {
+        Some(etag) => if_none_match.split(',').any(|t| opaque(t) == opaque(etag)),
+        None => false,
+    }
+}
+
+fn unmodified_since(if_modified_since: &HeaderValue, last_modified: Option<&HeaderValue>) -> bool {
+    let parse = |v: &HeaderValue| v.to_str().ok().and_then(dates::parse);
+    match (parse(if_modified_since), last_modified.and_then(parse)) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+/// The status to answer a conditional request with instead of a `200 OK` response
+/// with `headers`, if a condition fails: `304 Not Modified` when a GET or HEAD client's
+/// cached copy is still good, or `412 Precondition Failed` when another method's
+/// `If-None-Match` matches.  `If-None-Match` wins over `If-Modified-Since`, which only
+/// counts for GET and HEAD requests
+pub fn precondition(
+    req: &RequestParts,
+    status: StatusCode,
+    headers: &HeaderMap,
+) -> Option<StatusCode> {
+    if status != StatusCode::OK {
+        return None;
+    }
+    let cacheable = req.method == Method::GET || req.method == Method::HEAD;
+    if let Some(v) = req.headers.get(header::IF_NONE_MATCH) {
+        return match (matches_etag(v, headers.get(header::ETAG)), cacheable) {
+            (true, true) => Some(StatusCode::NOT_MODIFIED),
+            (true, false) => Some(StatusCode::PRECONDITION_FAILED),
+            (false, _) => None,
+        };
+    }
+    match req.headers.get(header::IF_MODIFIED_SINCE) {
+        Some(v) if cacheable && unmodified_since(v, headers.get(header::LAST_MODIFIED)) => {
+            Some(StatusCode::NOT_MODIFIED)
+        }
+        _ => None,
+    }
+}
+
+/// An empty `status` response in place of `response`.  A 304 keeps the headers that
+/// describe the client's cached copy
+pub fn precondition_response(
+    response: Response<ReplayBody>,
+    status: StatusCode,
+) -> Response<ReplayBody> {
+    let (mut parts, _) = response.into_parts();
+    let mut headers = HeaderMap::new();
+    if status == StatusCode::NOT_MODIFIED {
+        for name in KEPT {
+            for v in parts.headers.get_all(name) {
+                headers.append(name.clone(), v.clone());
+            }
+        }
+    }
+    parts.status = status;
+    parts.headers = headers;
+    Response::from_parts(parts, ReplayBody::from(Body::empty()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::precondition;
+    use hyper::header::{self, HeaderMap, HeaderValue};
+    use hyper::{Request, StatusCode};
+
+    #[test]
+    fn test_precondition() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ETAG, HeaderValue::from_static("W/\"v2\""));
+        headers.insert(
+            header::LAST_MODIFIED,
+            HeaderValue::from_static("Tue, 06 Dec 1994 08:49:37 GMT"),
+        );
+        let check = |method: &str, name, value| {
+            let (parts, _) = Request::builder()
+                .method(method)
+                .uri("/items")
+                .header(name, value)
+                .body(())
+                .unwrap()
+                .into_parts();
+            precondition(&parts, StatusCode::OK, &headers)
+        };
+        let not_modified = Some(StatusCode::NOT_MODIFIED);
+        assert_eq!(
+            not_modified,
+            check("GET", header::IF_NONE_MATCH, "\"v1\", \"v2\"")
+        );
+        assert_eq!(not_modified, check("HEAD", header::IF_NONE_MATCH, "*"));
+        assert_eq!(None, check("GET", header::IF_NONE_MATCH, "\"v1\""));
+        assert_eq!(
+            Some(StatusCode::PRECONDITION_FAILED),
+            check("PUT", header::IF_NONE_MATCH, "*")
+        );
+        assert_eq!(None, check("PUT", header::IF_NONE_MATCH, "\"v1\""));
+        let since = "Wed, 07 Dec 1994 00:00:00 GMT";
+        assert_eq!(not_modified, check("GET", header::IF_MODIFIED_SINCE, since));
+        assert_eq!(None, check("POST", header::IF_MODIFIED_SINCE, since));
+        let before = "Mon, 05 Dec 1994 00:00:00 GMT";
+        assert_eq!(None, check("HEAD", header::IF_MODIFIED_SINCE, before));
+    }
+}
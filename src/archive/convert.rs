@@ -4,7 +4,7 @@ use cookie::Cookie;
 use failure::Error;
 use har::v1_2::*;
 use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
-use hyper::{Body, Chunk, Version};
+use hyper::Version;
 use std::borrow::Cow;
 use std::convert::From;
 
@@ -56,7 +56,7 @@ impl Header {
     }
 }
 
-fn maybe_encode(b: Vec<u8>) -> (String, bool) {
+pub fn maybe_encode(b: Vec<u8>) -> (String, bool) {
     match String::from_utf8(b) {
         Ok(s) => (s, false),
         Err(e) => (base64::encode(e.as_bytes()), true),
@@ -66,23 +66,20 @@ fn maybe_encode(b: Vec<u8>) -> (String, bool) {
 pub struct RequestBody;
 
 impl RequestBody {
-    pub fn har(body: Vec<u8>, mime_type: String) -> Option<PostData> {
-        if body.is_empty() {
-            None
-        } else {
-            let (text, base64_encoded) = maybe_encode(body);
-            Some(PostData {
-                mime_type,
-                text,
-                params: None,
-                comment: if base64_encoded {
-                    Some("base64".into())
-                } else {
-                    None
-                },
-            })
+    /// Post data holding `text` as returned by `maybe_encode`
+    pub fn har((text, base64_encoded): (String, bool), mime_type: String) -> PostData {
+        PostData {
+            mime_type,
+            text,
+            params: None,
+            comment: if base64_encoded {
+                Some("base64".into())
+            } else {
+                None
+            },
         }
     }
+
     pub fn bytes(data: &PostData) -> Result<(Vec<u8>, String), Error> {
         let encoding = data.mime_type.to_string();
         let body = if let Some(c) = &data.comment {
@@ -101,9 +98,8 @@ impl RequestBody {
 pub struct ResponseBody;
 
 impl ResponseBody {
-    pub fn har(body: Vec<u8>, mime_type: String) -> Content {
-        let size = body.len() as i64;
-        let (text, encoded) = maybe_encode(body);
+    /// Content of `size` bytes holding `text` as returned by `maybe_encode`
+    pub fn har(size: i64, (text, encoded): (String, bool), mime_type: String) -> Content {
         Content {
             size,
             compression: None,
@@ -114,22 +110,22 @@ impl ResponseBody {
         }
     }
 
-    pub fn hyper(content: &Content) -> Result<(Body, String), Error> {
-        if content.text.is_none() {
-            return Ok((Body::empty(), "".to_string()));
-        }
-        let text = content.text.as_ref().map(|t| t.to_string()).unwrap();
+    pub fn bytes(content: &Content) -> Result<(Vec<u8>, String), Error> {
         let mime_type = content.mime_type.to_string();
+        let text = match &content.text {
+            Some(t) => t,
+            None => return Ok((Vec::new(), mime_type)),
+        };
         let body = if let Some(e) = &content.encoding {
             if e == "base64" {
-                base64::decode(&text)?
+                base64::decode(text)?
             } else {
                 text.as_bytes().to_vec()
             }
         } else {
             text.as_bytes().to_vec()
         };
-        Ok((Body::from(Chunk::from(body)), mime_type))
+        Ok((body, mime_type))
     }
 }
 
@@ -0,0 +1,106 @@
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use hyper::header::{self, HeaderMap, HeaderValue};
+use regex::{Captures, Regex};
+
+/// Formats HTTP dates have been sent in: the preferred IMF-fixdate, the one cookies
+/// often use, RFC 850 and asctime
+const FORMATS: &[&str] = &[
+    "%a, %d %b %Y %H:%M:%S GMT",
+    "%a, %d-%b-%Y %H:%M:%S GMT",
+    "%A, %d-%b-%y %H:%M:%S GMT",
+    "%a %b %e %H:%M:%S %Y",
+];
+
+const IMF_FIXDATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+lazy_static! {
+    static ref COOKIE_EXPIRES: Regex = Regex::new(r"(?i)(;\s*expires=)([^;]+)").unwrap();
+}
+
+pub fn parse(date: &str) -> Option<NaiveDateTime> {
+    FORMATS
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(date.trim(), f).ok())
+}
+
+/// `at` as an HTTP date
+pub fn format(at: DateTime<Utc>) -> String {
+    at.format(IMF_FIXDATE).to_string()
+}
+
+/// `date` moved `by`, or `None` if it isn't an HTTP date (like an `Expires` of `0`)
+fn shift(date: &str, by: Duration) -> Option<String> {
+    Some((parse(date)? + by).format(IMF_FIXDATE).to_string())
+}
+
+/// Move the dates in the `Expires` and `Last-Modified` headers, and the `Expires` of
+/// cookies, `by` the same amount
+pub fn shift_headers(headers: &mut HeaderMap, by: Duration) {
+    for name in &[header::EXPIRES, header::LAST_MODIFIED] {
+        let shifted = headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| shift(v, by))
+            .and_then(|v| HeaderValue::from_str(&v).ok());
+        if let Some(v) = shifted {
+            headers.insert(name.clone(), v);
+        }
+    }
+    let cookies: Vec<HeaderValue> = headers
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .map(|v| {
+            let shifted = v.to_str().ok().and_then(|c| {
+                let c = COOKIE_EXPIRES.replace(c, |caps: &Captures| {
+                    let expires = shift(&caps[2], by).unwrap_or_else(|| caps[2].to_string());
+                    format!("{}{}", &caps[1], expires)
+                });
+                HeaderValue::from_str(&c).ok()
+            });
+            shifted.unwrap_or_else(|| v.clone())
+        })
+        .collect();
+    if !cookies.is_empty() {
+        headers.remove(header::SET_COOKIE);
+        for c in cookies {
+            headers.append(header::SET_COOKIE, c);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::shift_headers;
+    use chrono::Duration;
+    use hyper::header::{self, HeaderMap, HeaderValue};
+
+    #[test]
+    fn test_shift_headers() {
+        let mut headers = HeaderMap::new();
+        let mut add = |name, value| headers.append(name, HeaderValue::from_static(value));
+        add(header::DATE, "Sun, 06 Nov 1994 08:49:37 GMT");
+        add(header::EXPIRES, "0");
+        add(header::LAST_MODIFIED, "Sunday, 30-Oct-94 08:49:37 GMT");
+        add(
+            header::SET_COOKIE,
+            "session=abc; Expires=Wed, 09-Nov-1994 08:49:37 GMT; Path=/",
+        );
+        add(header::SET_COOKIE, "theme=dark");
+        shift_headers(&mut headers, Duration::days(30));
+
+        assert_eq!("Sun, 06 Nov 1994 08:49:37 GMT", headers[header::DATE]);
+        assert_eq!("0", headers[header::EXPIRES]);
+        assert_eq!(
+            "Tue, 29 Nov 1994 08:49:37 GMT",
+            headers[header::LAST_MODIFIED]
+        );
+        let cookies: Vec<_> = headers.get_all(header::SET_COOKIE).iter().collect();
+        assert_eq!(
+            vec![
+                "session=abc; Expires=Fri, 09 Dec 1994 08:49:37 GMT; Path=/",
+                "theme=dark"
+            ],
+            cookies
+        );
+    }
+}
@@ -0,0 +1,190 @@
+use har::v1_2::Headers;
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+
+/// Talkboy-specific data that HAR 1.2 has no field for.  It is stored as a JSON
+/// object in the `comment` field of the entry.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EntryExtensions {
+    /// Body chunks of a streamed response, in the order they were received
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<ChunkTiming>,
+    /// Frames exchanged after a WebSocket handshake
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub websocket: Vec<WebSocketMessage>,
+    /// Headers sent after the response body
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trailers: Vec<Headers>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grpc: Option<GrpcCall>,
+    /// The route of the project that sent the request upstream
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub route: Option<RouteTag>,
+    /// Why the upstream couldn't be reached, when talkboy made up the response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_error: Option<String>,
+    /// Fill in `{{ ... }}` templates in the headers and body when playing back
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub template: bool,
+    /// Requests the recording answers instead of only the path it was recorded with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<PathPattern>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkTiming {
+    /// Milliseconds from the start of the response
+    pub offset: u64,
+    /// Length of the chunk in bytes, as sent on the wire
+    pub size: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Client to server
+    Send,
+    /// Server to client
+    Receive,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebSocketMessage {
+    #[serde(rename = "type")]
+    pub direction: Direction,
+    /// Milliseconds from the end of the handshake
+    pub time: u64,
+    pub opcode: u8,
+    #[serde(default = "default_fin", skip_serializing_if = "is_fin")]
+    pub fin: bool,
+    /// The payload, base64-encoded if `encoding` is `"base64"`
+    pub data: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+}
+
+/// The messages of a gRPC call, in the order they were sent
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GrpcCall {
+    pub service: String,
+    pub method: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub request: Vec<GrpcMessage>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub response: Vec<GrpcMessage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GrpcMessage {
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub compressed: bool,
+    /// The message decoded with the project's descriptor set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub json: Option<serde_json::Value>,
+    /// The base64-encoded message, when it couldn't be decoded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouteTag {
+    pub name: String,
+    /// The path and query the client asked for, when the route changed them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+}
+
+/// A family of paths a recording matches, with their parameters captured
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PathPattern {
+    /// The path and query with `{name}` placeholders, each standing for a path segment
+    /// or query value
+    Template(String),
+    /// Matched against the whole path and query; named groups are captured
+    Regex(String),
+}
+
+impl PathPattern {
+    pub fn compile(&self) -> Result<Regex, regex::Error> {
+        match self {
+            PathPattern::Template(t) => {
+                let mut pattern = String::from("^");
+                let mut rest = t.as_str();
+                while let Some(start) = rest.find('{') {
+                    let end = match rest[start..].find('}') {
+                        Some(end) => start + end,
+                        None => break,
+                    };
+                    pattern.push_str(&regex::escape(&rest[..start]));
+                    pattern.push_str(&format!("(?P<{}>[^/?&#]+)", &rest[start + 1..end]));
+                    rest = &rest[end + 1..];
+                }
+                pattern.push_str(&regex::escape(rest));
+                pattern.push('$');
+                Regex::new(&pattern)
+            }
+            PathPattern::Regex(r) => Regex::new(&format!("^(?:{})$", r)),
+        }
+    }
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+fn default_fin() -> bool {
+    true
+}
+
+fn is_fin(fin: &bool) -> bool {
+    *fin
+}
+
+impl EntryExtensions {
+    pub fn is_empty(&self) -> bool {
+        *self == EntryExtensions::default()
+    }
+
+    /// Comments that aren't ours (or are from older recordings) are ignored
+    pub fn from_comment(comment: &Option<String>) -> EntryExtensions {
+        comment
+            .as_ref()
+            .and_then(|c| serde_json::from_str(c).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn to_comment(&self) -> Option<String> {
+        if self.is_empty() {
+            None
+        } else {
+            serde_json::to_string(self).ok()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PathPattern;
+
+    #[test]
+    fn test_path_pattern() {
+        let users = PathPattern::Template("/users/{id}/orders?page={page}".to_string())
+            .compile()
+            .unwrap();
+        let c = users.captures("/users/42/orders?page=3").unwrap();
+        assert_eq!(("42", "3"), (&c["id"], &c["page"]));
+        assert!(!users.is_match("/users/42/orders"));
+        assert!(!users.is_match("/users/4/2/orders?page=3"));
+        assert!(!users.is_match("/api/users/42/orders?page=3"));
+
+        let items = PathPattern::Regex(r"/items/(?P<sku>\d+)".to_string())
+            .compile()
+            .unwrap();
+        assert!(items.is_match("/items/7"));
+        assert!(!items.is_match("/items/7/reviews"));
+        assert!(PathPattern::Template("/{a b}".to_string())
+            .compile()
+            .is_err());
+    }
+}
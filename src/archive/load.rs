@@ -1,6 +1,9 @@
 #![allow(unreachable_patterns)]
+use super::compression::ContentEncoding;
 use super::convert;
-use super::{ArchivedRequest, RequestFacts};
+use super::extensions::EntryExtensions;
+use super::{ArchivedRequest, RequestFacts, IGNORED_DIR};
+use chrono::{DateTime, Utc};
 use failure::Error;
 use har::v1_2::*;
 use har::{Har, Spec};
@@ -22,12 +25,38 @@ pub enum HarLoadingError {
     InvalidMatcher(String),
 }
 
+fn request_encoding(r: &Request) -> Option<ContentEncoding> {
+    r.headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("content-encoding"))
+        .and_then(|h| ContentEncoding::from_name(&h.value))
+}
+
 pub struct HarLoader {
     logger: Logger,
+    include_ignored: bool,
+    decode_grpc: bool,
 }
 impl HarLoader {
     pub fn new(logger: Logger) -> HarLoader {
-        HarLoader { logger }
+        HarLoader {
+            logger,
+            include_ignored: false,
+            decode_grpc: false,
+        }
+    }
+
+    /// Match gRPC calls on their JSON messages, for playback that has the descriptors
+    /// to decode incoming requests with.  Otherwise they're matched on the raw body.
+    pub fn decode_grpc(mut self, decode: bool) -> HarLoader {
+        self.decode_grpc = decode;
+        self
+    }
+
+    /// Also load the responses quarantined in `_ignored/`
+    pub fn include_ignored(mut self, include: bool) -> HarLoader {
+        self.include_ignored = include;
+        self
     }
 
     fn find_requests<P: AsRef<Path>>(&self, path: P) -> IoResult<Vec<PathBuf>> {
@@ -79,6 +108,11 @@ impl HarLoader {
 
         if let Some(d) = &r.post_data {
             let (data, content_type) = convert::RequestBody::bytes(&d)?;
+            // Older recordings stored compressed request bodies as-is
+            let data = match request_encoding(r) {
+                Some(enc) => enc.decode(&data).unwrap_or(data),
+                None => data,
+            };
             results.push(RequestFacts::Body { data, content_type });
         }
 
@@ -93,21 +127,114 @@ impl HarLoader {
         } else {
             Duration::from_millis(e.time as u64)
         };
+        let extensions = EntryExtensions::from_comment(&e.comment);
+        // Older recordings don't say how long the body took, but a streamed one's
+        // chunks do
+        let receive_timing = match extensions.chunks.last() {
+            _ if e.timings.receive >= 0 => Duration::from_millis(e.timings.receive as u64),
+            Some(c) => Duration::from_millis(c.offset),
+            None => timing,
+        };
+        let mut facts = self.get_facts(&e.request)?;
+        // Decoded gRPC messages are matched instead of the raw body, so they can be
+        // edited (and are matched regardless of how they were serialized)
+        let grpc_json = extensions.grpc.as_ref().and_then(|g| g.request_json());
+        if let Some(messages) = grpc_json.filter(|_| self.decode_grpc) {
+            facts.retain(|f| !matches!(f, RequestFacts::Body { .. }));
+            facts.push(RequestFacts::GrpcRequest(messages));
+        }
+        // Clients keep asking for the path they used before the route rewrote it
+        if let Some(path) = extensions.route.as_ref().and_then(|r| r.path.clone()) {
+            facts.retain(|f| !matches!(f, RequestFacts::PathAndQuery(_)));
+            facts.push(RequestFacts::PathAndQuery(path));
+        }
+        let pattern = match extensions.pattern {
+            Some(ref p) => Some(p.compile().map_err(|e| {
+                HarLoadingError::InvalidMatcher(format!("Invalid path pattern {:?}: {}", p, e))
+            })?),
+            None => None,
+        };
         Ok(ArchivedRequest {
             original_timing: timing,
-            facts: self.get_facts(&e.request)?,
+            receive_timing,
+            facts,
+            pattern,
             response: e.response.clone(),
+            extensions,
+            request_url: e.request.url.clone(),
+            date_shift: DateTime::parse_from_rfc3339(&e.started_date_time)
+                .ok()
+                .map(|d| Utc::now().signed_duration_since(d)),
         })
     }
 
     pub fn load_all<P: AsRef<Path>>(&self, path: P) -> Result<Vec<ArchivedRequest>, Error> {
         let path = path.as_ref();
         trace!(self.logger, "Loading all interactions from {:?}", &path);
-        let files = self.find_requests(&path)?;
+        let mut files = self.find_requests(path)?;
+        let ignored = path.join(IGNORED_DIR);
+        if self.include_ignored && ignored.is_dir() {
+            files.extend(self.find_requests(&ignored)?);
+        }
+        // One broken recording shouldn't keep the rest from being played back
         let mut results = Vec::new();
         for f in files {
-            results.extend(self.load(&f)?);
+            match self.load(&f) {
+                Ok(r) => results.extend(r),
+                Err(e) => {
+                    let fname = f.to_string_lossy().into_owned();
+                    error!(self.logger, "Skipping recording: {}", e; "path" => fname);
+                }
+            }
         }
         Ok(results)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::HarLoader;
+    use serde_json::json;
+    use slog::{Discard, Logger};
+    use std::fs;
+
+    #[test]
+    fn test_load_all_skips_broken_files() {
+        let entry = |comment: &str| {
+            json!({ "log": {
+                "version": "1.2",
+                "creator": { "name": "Talkboy", "version": "0.1.0" },
+                "entries": [{
+                    "startedDateTime": "2019-02-01T12:00:00+00:00",
+                    "time": 20,
+                    "request": {
+                        "method": "GET", "url": "http://example.com/users/42",
+                        "httpVersion": "HTTP/1.1", "cookies": [], "headers": [],
+                        "queryString": [], "headersSize": -1, "bodySize": -1
+                    },
+                    "response": {
+                        "status": 200, "statusText": "OK", "httpVersion": "HTTP/1.1",
+                        "cookies": [], "headers": [],
+                        "content": { "size": 2, "mimeType": "text/plain", "text": "hi" },
+                        "redirectURL": "", "headersSize": -1, "bodySize": -1
+                    },
+                    "cache": {},
+                    "timings": { "send": 0, "wait": 10, "receive": 10 },
+                    "comment": comment
+                }]
+            }})
+            .to_string()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let pattern = |t: &str| json!({ "pattern": { "template": t } }).to_string();
+        fs::write(dir.path().join("good.json"), entry(&pattern("/users/{id}"))).unwrap();
+        // The same placeholder twice makes an invalid regex
+        let twice = entry(&pattern("/users/{id}/{id}"));
+        fs::write(dir.path().join("bad.json"), twice).unwrap();
+
+        let loader = HarLoader::new(Logger::root(Discard, o!()));
+        let loaded = loader.load_all(dir.path()).unwrap();
+        assert_eq!(1, loaded.len());
+        assert!(loaded[0].is_pattern());
+    }
+}
@@ -1,25 +1,59 @@
+mod compression;
+mod conditional;
 mod convert;
+mod dates;
+mod extensions;
 mod load;
+mod spooled;
 mod store;
 
+use bytes::Bytes;
+use chrono::Utc;
 use failure::Error;
+use futures::{future, stream, task, try_ready, Async, Future, Poll, Stream};
 use har::v1_2::Response as HarResponse;
-use hyper::header::{HeaderName, HeaderValue};
-use hyper::http::Method;
-use hyper::{Body, Response as HyperResponse};
+use hyper::body::Payload;
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::http::request::Parts as RequestParts;
+use hyper::http::{Method, StatusCode};
+use hyper::{Body, Chunk, Response as HyperResponse, Uri, Version};
+use regex::Regex;
 
 use crate::config::DelayOptions;
+use crate::grpc::Descriptors;
+use crate::latency::{Bandwidth, Latency};
+use crate::rewrite::{self, OriginRewrite, Replacer};
+use crate::template::TemplateContext;
+use crate::trailers::TrailerSlot;
+pub use compression::{ContentEncoding, Recoder};
+pub use conditional::{precondition, precondition_response};
+pub use extensions::{
+    ChunkTiming, Direction, EntryExtensions, GrpcCall, GrpcMessage, PathPattern, RouteTag,
+    WebSocketMessage,
+};
 pub use load::{HarLoader, HarLoadingError};
+pub use spooled::SpooledBody;
+use std::error::Error as StdError;
+use std::io;
+use std::mem;
 use std::time::{Duration, Instant};
-pub use store::{HarSession, IncompleteEntryError};
+pub use store::{write_pattern, HarSession, IncompleteEntryError};
 use tokio::timer::Delay;
 
+/// Subdirectory of a project's recordings that quarantined responses are written to
+pub const IGNORED_DIR: &str = "_ignored";
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum RequestFacts {
     Method(Method),
     PathAndQuery(String),
-    Body { content_type: String, data: Vec<u8> },
+    Body {
+        content_type: String,
+        data: Vec<u8>,
+    },
     Headers(Vec<(HeaderName, HeaderValue)>),
+    /// gRPC request messages decoded to JSON
+    GrpcRequest(Vec<String>),
 }
 
 impl RequestFacts {
@@ -28,35 +62,376 @@ impl RequestFacts {
     }
 }
 
+/// A recorded body followed by its recorded trailers
+pub struct ReplayBody {
+    body: Body,
+    trailers: Option<HeaderMap>,
+    slot: Option<TrailerSlot>,
+    len: Option<u64>,
+    /// Bytes left to send before failing the body, when it's being truncated
+    remaining: Option<u64>,
+    /// Whether the connection was given a chance to write out the truncated body
+    yielded: bool,
+    chunked: bool,
+}
+
+impl ReplayBody {
+    fn new(body: Body, len: Option<u64>, trailers: Option<HeaderMap>) -> ReplayBody {
+        ReplayBody {
+            body,
+            trailers,
+            slot: None,
+            len,
+            remaining: None,
+            yielded: false,
+            chunked: false,
+        }
+    }
+
+    /// Leave the trailers in `slot` once the body ends, for an HTTP/1 connection to write
+    pub fn trailer_slot(&mut self, slot: TrailerSlot) {
+        self.slot = Some(slot);
+    }
+
+    /// Fail the body once half of it has been sent
+    pub fn truncate(&mut self) {
+        self.remaining = Some(self.len.unwrap_or(0) / 2);
+    }
+
+    /// Don't announce a length, so an HTTP/1 body is sent chunked
+    pub fn chunked(&mut self) {
+        self.chunked = true;
+    }
+
+    /// Send the body no faster than `bytes_per_second`
+    pub fn throttle(&mut self, bytes_per_second: u64) {
+        let body = mem::replace(&mut self.body, Body::empty());
+        self.body = Body::wrap_stream(Throttle::new(body, bytes_per_second));
+    }
+}
+
+/// Sends a body in pieces, each once it would have finished arriving at `rate` bytes
+/// a second
+struct Throttle {
+    body: Body,
+    rate: u64,
+    started: Option<Instant>,
+    sent: u64,
+    pending: Bytes,
+    wait: Option<Delay>,
+}
+
+impl Throttle {
+    fn new(body: Body, rate: u64) -> Throttle {
+        Throttle {
+            body,
+            rate,
+            started: None,
+            sent: 0,
+            pending: Bytes::new(),
+            wait: None,
+        }
+    }
+}
+
+impl Stream for Throttle {
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        loop {
+            if let Some(ref mut wait) = self.wait {
+                // The timer only fails when it's shutting down, so just carry on
+                if let Ok(Async::NotReady) = wait.poll() {
+                    return Ok(Async::NotReady);
+                }
+                self.wait = None;
+            }
+            if !self.pending.is_empty() {
+                // Ten pieces a second keeps progress smooth without tiny writes
+                let size = ((self.rate / 10).max(1) as usize).min(self.pending.len());
+                let started = *self.started.get_or_insert_with(Instant::now);
+                let sent = self.sent + size as u64;
+                let due = started + Duration::from_micros(sent * 1_000_000 / self.rate);
+                if due > Instant::now() {
+                    self.wait = Some(Delay::new(due));
+                    continue;
+                }
+                self.sent = sent;
+                return Ok(Async::Ready(Some(Chunk::from(self.pending.split_to(size)))));
+            }
+            match try_ready!(self.body.poll_data()) {
+                Some(chunk) => self.pending = chunk.into_bytes(),
+                None => return Ok(Async::Ready(None)),
+            }
+        }
+    }
+}
+
+impl From<Body> for ReplayBody {
+    fn from(body: Body) -> ReplayBody {
+        let len = body.content_length();
+        ReplayBody::new(body, len, None)
+    }
+}
+
+impl Payload for ReplayBody {
+    type Data = Chunk;
+    type Error = Box<dyn StdError + Send + Sync>;
+
+    fn poll_data(&mut self) -> Poll<Option<Self::Data>, Self::Error> {
+        if self.remaining == Some(0) {
+            // hyper drops what it has buffered when the body fails
+            if !self.yielded {
+                self.yielded = true;
+                task::current().notify();
+                return Ok(Async::NotReady);
+            }
+            let e = io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Truncated by an injected fault",
+            );
+            return Err(e.into());
+        }
+        let data = match (self.remaining, try_ready!(self.body.poll_data())) {
+            (Some(remaining), Some(chunk)) => {
+                let mut bytes = chunk.into_bytes();
+                let keep = remaining.min(bytes.len() as u64);
+                bytes.truncate(keep as usize);
+                self.remaining = Some(remaining - keep);
+                Some(Chunk::from(bytes))
+            }
+            (_, data) => data,
+        };
+        if data.is_none() {
+            if let (Some(slot), Some(ref t)) = (self.slot.take(), &self.trailers) {
+                slot.put(t.clone());
+            }
+        }
+        Ok(Async::Ready(data))
+    }
+
+    fn poll_trailers(&mut self) -> Poll<Option<HeaderMap>, Self::Error> {
+        Ok(Async::Ready(self.trailers.take()))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        // A truncated body always ends in an error
+        self.remaining.is_none() && self.body.is_end_stream() && self.trailers.is_none()
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        // Only a chunked HTTP/1 body has room for trailers
+        match self.trailers {
+            Some(_) => None,
+            None if self.chunked => None,
+            None => self.body.content_length(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ArchivedRequest {
     original_timing: Duration,
+    /// How long the body took to arrive, the last part of `original_timing`
+    receive_timing: Duration,
     facts: Vec<RequestFacts>,
+    /// Matched against the path and query instead of the recorded one
+    pattern: Option<Regex>,
     response: HarResponse,
+    extensions: EntryExtensions,
+    /// Where the request was sent when it was recorded
+    request_url: String,
+    /// How far to move the recorded dates forward: from when the request was sent to
+    /// when the recording was loaded, if the recording says when it was sent
+    date_shift: Option<chrono::Duration>,
 }
 
 impl ArchivedRequest {
-    pub fn hyper_response(&self) -> Result<HyperResponse<Body>, Error> {
-        let mut builder = HyperResponse::builder();
-        builder.status(self.response.status as u16);
-        builder.version(convert::HttpVersion::hyper(&self.response.http_version)?);
+    pub fn hyper_response(
+        &self,
+        req: &RequestParts,
+        req_body: &[u8],
+        delay: &DelayOptions,
+        descriptors: Option<&Descriptors>,
+        rewrite: Option<&OriginRewrite>,
+    ) -> Result<HyperResponse<ReplayBody>, Error> {
+        let mut headers = HeaderMap::new();
         for h in &self.response.headers {
-            let (k, v) = convert::Header::hyper(&h)?;
-            builder.header(k, v);
+            let (k, v) = convert::Header::hyper(h)?;
+            headers.append(k, v);
         }
         // ignoring the mime type from the Content object because the Content-Type header should
         // should have already been set
-        let (body, _mime_type) = convert::ResponseBody::hyper(&self.response.content)?;
-        Ok(builder.body(body)?)
+        let (mut body, _mime_type) = convert::ResponseBody::bytes(&self.response.content)?;
+        if let Some(ref call) = self.extensions.grpc {
+            // The recorded messages win over the raw body so they can be edited, but
+            // JSON messages can only be encoded again with the descriptors
+            let encodable = descriptors.is_some() || call.response.iter().all(|m| m.json.is_none());
+            if !call.response.is_empty() && encodable {
+                body = call.body(&call.response, false, descriptors)?;
+                headers.remove(header::CONTENT_LENGTH);
+            }
+        }
+        if self.extensions.template {
+            let context = TemplateContext::new(req, req_body).params(self.path_params(req));
+            for value in headers.values_mut() {
+                // A value echoed from the request can't always go in a header
+                let rendered = value
+                    .to_str()
+                    .ok()
+                    .and_then(|v| HeaderValue::from_str(&context.render(v)).ok());
+                if let Some(rendered) = rendered {
+                    *value = rendered;
+                }
+            }
+            // Bodies that aren't text are left alone
+            if let Ok(text) = std::str::from_utf8(&body) {
+                body = context.render(text).into_bytes();
+                headers.remove(header::CONTENT_LENGTH);
+            }
+        }
+        // Before compressing, so the decoded body is what's rewritten
+        if let Some(r) = rewrite {
+            let replacer = r
+                .listener_origin(&req.headers, &req.uri)
+                .and_then(|to| self.origin_replacer(&to));
+            if let Some(replacer) = replacer {
+                r.rewrite_headers(&replacer, &mut headers);
+                if r.rewrites_body(&headers) {
+                    if let Some(rewritten) = replacer.replace_all(&body) {
+                        body = rewritten;
+                        headers.remove(header::CONTENT_LENGTH);
+                    }
+                }
+            }
+        }
+        if self.response.content.compression.is_some() {
+            // The body was decoded when it was recorded, so re-compress it only if the
+            // client can take it, otherwise serve it as identity
+            headers.remove(header::CONTENT_LENGTH);
+            if let Some(enc) = ContentEncoding::from_headers(&headers) {
+                if enc.accepted_by(&req.headers) {
+                    body = enc.encode(&body)?;
+                } else {
+                    headers.remove(header::CONTENT_ENCODING);
+                }
+            }
+        }
+        let len = body.len() as u64;
+        let body = match delay {
+            DelayOptions::Original { scale } if !self.extensions.chunks.is_empty() => {
+                self.timed_body(body, *scale)
+            }
+            _ => Body::from(Chunk::from(body)),
+        };
+        let mut trailers = HeaderMap::new();
+        for h in &self.extensions.trailers {
+            let (k, v) = convert::Header::hyper(h)?;
+            trailers.append(k, v);
+        }
+        let trailers = if trailers.is_empty() {
+            None
+        } else {
+            headers.remove(header::CONTENT_LENGTH);
+            Some(trailers)
+        };
+        let body = ReplayBody::new(body, Some(len), trailers);
+        let mut response = HyperResponse::new(body);
+        *response.status_mut() = StatusCode::from_u16(self.response.status as u16)?;
+        // HTTP/2 is decided by the connection, not the recording
+        let version = convert::HttpVersion::hyper(&self.response.http_version)?;
+        *response.version_mut() = if version == Version::HTTP_2 || req.version == Version::HTTP_2 {
+            req.version
+        } else {
+            version
+        };
+        *response.headers_mut() = headers;
+        Ok(response)
+    }
+
+    /// Maps the origin the request was recorded from to `listener`, putting back
+    /// the prefix a route stripped
+    fn origin_replacer(&self, listener: &str) -> Option<Replacer> {
+        let uri: Uri = self.request_url.parse().ok()?;
+        let from = rewrite::origin(&uri)?;
+        let sent = uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+        let prefix = match self.extensions.route.as_ref().and_then(|r| r.path.as_ref()) {
+            Some(requested) => requested.strip_suffix(sent).unwrap_or(""),
+            None => "",
+        };
+        let to = format!("{}{}", listener, prefix);
+        Some(Replacer::new(vec![(from, to)]))
+    }
+
+    /// How fast to send the body with `bandwidth`, if it's limited
+    pub fn bytes_per_second(&self, bandwidth: Bandwidth) -> Option<u64> {
+        let size = self.response.content.size.max(0) as u64;
+        bandwidth.bytes_per_second(size, self.receive_timing)
+    }
+
+    pub fn is_websocket(&self) -> bool {
+        self.response.status == i64::from(StatusCode::SWITCHING_PROTOCOLS.as_u16())
+    }
+
+    pub fn websocket_messages(&self) -> &[WebSocketMessage] {
+        &self.extensions.websocket
+    }
+
+    /// Split the body back into the recorded chunks, each sent at its original offset
+    /// times `scale`.  Chunk sizes are as received, so a body that's been changed
+    /// since (decoded, say) is split at the same points in proportion.
+    fn timed_body(&self, mut body: Vec<u8>, scale: f64) -> Body {
+        let recorded = self.extensions.chunks.iter().map(|c| c.size).sum::<usize>();
+        let len = body.len();
+        let mut chunks = Vec::with_capacity(self.extensions.chunks.len());
+        let (mut received, mut sent) = (0, 0);
+        for c in &self.extensions.chunks {
+            received += c.size;
+            let end = if recorded == 0 {
+                len
+            } else {
+                (received as u128 * len as u128 / recorded as u128) as usize
+            };
+            let rest = body.split_off(end - sent);
+            sent = end;
+            // Nothing to wait for if nothing is sent
+            if !body.is_empty() {
+                let offset = Duration::from_millis(c.offset).mul_f64(scale);
+                chunks.push((offset, Chunk::from(body)));
+            }
+            body = rest;
+        }
+        // offsets are relative to when the body starts being sent, not when it's built
+        let s = future::lazy(|| Ok(Instant::now()))
+            .map(move |start| {
+                stream::iter_ok(chunks)
+                    .and_then(move |(offset, chunk)| Delay::new(start + offset).map(move |_| chunk))
+            })
+            .flatten_stream();
+        Body::wrap_stream(s)
     }
 
-    pub fn delay(&self, d: &DelayOptions) -> Delay {
+    pub fn delay(&self, d: &DelayOptions, latency: &Latency) -> Delay {
         match d {
             DelayOptions::None => Delay::new(Instant::now()),
-            DelayOptions::Original => Delay::new(Instant::now() + self.original_timing),
-            DelayOptions::Static { millis: ms } => {
-                Delay::new(Instant::now() + Duration::from_millis(*ms))
+            DelayOptions::Original { scale } => {
+                // The original timing runs until the end of the body, the chunk offsets
+                // account for the part after the headers
+                let body_time = self
+                    .extensions
+                    .chunks
+                    .last()
+                    .map(|c| Duration::from_millis(c.offset))
+                    .unwrap_or_else(|| Duration::from_millis(0));
+                let headers_time = if self.original_timing > body_time {
+                    self.original_timing - body_time
+                } else {
+                    Duration::from_millis(0)
+                };
+                Delay::new(Instant::now() + headers_time.mul_f64(*scale))
             }
+            _ => Delay::new(Instant::now() + latency.sample(d)),
         }
     }
 
@@ -70,6 +445,48 @@ impl ArchivedRequest {
                     None
                 }
             })
-            .all(|(f, o)| f == o)
+            .all(|(f, o)| match (f, &self.pattern) {
+                (RequestFacts::PathAndQuery(p), Some(pattern)) => pattern.is_match(p),
+                _ => f == o,
+            })
+    }
+
+    /// Move the recorded dates in `headers` forward by how long before playback started
+    /// the response was recorded, and date the response now.  The shift stays the same
+    /// for as long as the recording is played back, so a `Last-Modified` the client
+    /// cached stays valid for conditional requests
+    pub fn refresh_dates(&self, headers: &mut HeaderMap) {
+        if let Some(shift) = self.date_shift {
+            dates::shift_headers(headers, shift);
+        }
+        if headers.contains_key(header::DATE) {
+            let now = HeaderValue::from_str(&dates::format(Utc::now()));
+            headers.insert(
+                header::DATE,
+                now.expect("HTTP dates are valid header values"),
+            );
+        }
+    }
+
+    /// Whether the recording answers a family of paths rather than just its own
+    pub fn is_pattern(&self) -> bool {
+        self.pattern.is_some()
+    }
+
+    /// The values the path pattern captured from a request for the recording
+    fn path_params(&self, req: &RequestParts) -> Vec<(String, String)> {
+        let path = req.uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("");
+        let (pattern, captures) = match self.pattern {
+            Some(ref p) => match p.captures(path) {
+                Some(c) => (p, c),
+                None => return Vec::new(),
+            },
+            None => return Vec::new(),
+        };
+        pattern
+            .capture_names()
+            .flatten()
+            .filter_map(|name| Some((name.to_string(), captures.name(name)?.as_str().to_string())))
+            .collect()
     }
 }
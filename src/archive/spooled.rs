@@ -0,0 +1,148 @@
+use super::compression::ContentEncoding;
+use failure::Error;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::str;
+
+/// How much of a spooled body is read at a time
+const PIECE: usize = 64 * 1024;
+
+/// A body to record, either in memory or in the temp file it was spooled to once it
+/// grew too big.  Spooled bodies are decoded, checked and written out a piece at a
+/// time rather than read back into memory.
+pub enum SpooledBody {
+    Memory(Vec<u8>),
+    File(File),
+}
+
+impl From<Vec<u8>> for SpooledBody {
+    fn from(body: Vec<u8>) -> SpooledBody {
+        SpooledBody::Memory(body)
+    }
+}
+
+impl SpooledBody {
+    fn reader(&self) -> io::Result<Box<dyn Read + '_>> {
+        match self {
+            SpooledBody::Memory(b) => Ok(Box::new(&b[..])),
+            SpooledBody::File(f) => {
+                let mut f = f;
+                f.seek(SeekFrom::Start(0))?;
+                Ok(Box::new(f))
+            }
+        }
+    }
+
+    pub fn len(&self) -> io::Result<u64> {
+        match self {
+            SpooledBody::Memory(b) => Ok(b.len() as u64),
+            SpooledBody::File(f) => Ok(f.metadata()?.len()),
+        }
+    }
+
+    pub fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// The whole body, for the uses that need it all at once
+    pub fn into_bytes(self) -> io::Result<Vec<u8>> {
+        match self {
+            SpooledBody::Memory(b) => Ok(b),
+            body => {
+                let mut data = Vec::new();
+                body.reader()?.read_to_end(&mut data)?;
+                Ok(data)
+            }
+        }
+    }
+
+    /// The body with `encoding` undone.  A spooled body is decoded into another temp file
+    pub fn decoded(&self, encoding: ContentEncoding) -> Result<SpooledBody, Error> {
+        match self {
+            SpooledBody::Memory(b) => Ok(SpooledBody::Memory(encoding.decode(b)?)),
+            SpooledBody::File(_) => {
+                let mut file = tempfile::tempfile()?;
+                io::copy(&mut encoding.decoder(self.reader()?), &mut file)?;
+                Ok(SpooledBody::File(file))
+            }
+        }
+    }
+
+    /// Hand every piece of the body to `f` in order
+    pub fn for_each<F: FnMut(&[u8])>(&self, mut f: F) -> io::Result<()> {
+        let mut reader = self.reader()?;
+        let mut buf = vec![0; PIECE];
+        loop {
+            match reader.read(&mut buf)? {
+                0 => return Ok(()),
+                n => f(&buf[..n]),
+            }
+        }
+    }
+
+    pub fn is_utf8(&self) -> io::Result<bool> {
+        let mut reader = self.reader()?;
+        let mut buf = vec![0; PIECE];
+        // Bytes of a character split between pieces, moved to the front of `buf`
+        let mut kept = 0;
+        loop {
+            let n = reader.read(&mut buf[kept..])?;
+            if n == 0 {
+                return Ok(kept == 0);
+            }
+            let end = kept + n;
+            match str::from_utf8(&buf[..end]) {
+                Ok(_) => kept = 0,
+                Err(e) if e.error_len().is_none() => {
+                    buf.copy_within(e.valid_up_to()..end, 0);
+                    kept = end - e.valid_up_to();
+                }
+                Err(_) => return Ok(false),
+            }
+        }
+    }
+
+    /// Write the body as the inside of a JSON string, base64 encoded or escaped
+    pub fn write_json<W: Write>(&self, out: &mut W, base64: bool) -> io::Result<()> {
+        let mut reader = self.reader()?;
+        if base64 {
+            let mut encoder = base64::write::EncoderWriter::new(out, base64::STANDARD);
+            io::copy(&mut reader, &mut encoder)?;
+            encoder.finish()
+        } else {
+            io::copy(&mut reader, &mut JsonEscape(out))?;
+            Ok(())
+        }
+    }
+}
+
+/// Escapes what's written through it for the inside of a JSON string.  Only ASCII
+/// bytes need escaping, so UTF-8 characters split between writes come out whole.
+struct JsonEscape<'a, W: Write>(&'a mut W);
+
+impl<'a, W: Write> Write for JsonEscape<'a, W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let mut start = 0;
+        for (i, &b) in data.iter().enumerate() {
+            if b != b'"' && b != b'\\' && b >= 0x20 {
+                continue;
+            }
+            self.0.write_all(&data[start..i])?;
+            match b {
+                b'"' => self.0.write_all(b"\\\"")?,
+                b'\\' => self.0.write_all(b"\\\\")?,
+                b'\n' => self.0.write_all(b"\\n")?,
+                b'\r' => self.0.write_all(b"\\r")?,
+                b'\t' => self.0.write_all(b"\\t")?,
+                b => write!(self.0, "\\u{:04x}", b)?,
+            }
+            start = i + 1;
+        }
+        self.0.write_all(&data[start..])?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
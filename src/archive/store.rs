@@ -1,17 +1,26 @@
+use super::compression::ContentEncoding;
 use super::convert;
+use super::extensions::{
+    ChunkTiming, EntryExtensions, GrpcCall, PathPattern, RouteTag, WebSocketMessage,
+};
+use super::load::HarLoadingError;
+use super::spooled::SpooledBody;
 use crate::VERSION;
 use chrono::prelude::*;
 use failure::Error;
 use har::v1_2::*;
 use har::{Har, Spec};
-use hyper::header;
+use hyper::header::{self, HeaderMap};
 use hyper::http::request::Parts as ReqParts;
 use hyper::http::response::Parts as ResParts;
 use regex::Regex;
-use serde_json;
+use serde_json::{self, Value};
 use sha2::{Digest, Sha256};
 use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::mem;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 pub struct HarSession {
     har: Har,
@@ -19,6 +28,12 @@ pub struct HarSession {
     request: Option<Request>,
     response: Option<Response>,
     request_hash: Option<String>,
+    extensions: EntryExtensions,
+    /// How long the response body took to arrive after its head
+    receive: Option<Duration>,
+    /// Bodies too big to hold in the HAR, with their placeholders and whether
+    /// they're base64 encoded
+    spooled: Vec<(String, SpooledBody, bool)>,
 }
 
 impl HarSession {
@@ -43,6 +58,9 @@ impl HarSession {
             request: None,
             response: None,
             request_hash: None,
+            extensions: EntryExtensions::default(),
+            receive: None,
+            spooled: Vec::new(),
         }
     }
 
@@ -68,7 +86,18 @@ impl HarSession {
         self.get_log_mut().entries.push(entry);
     }
 
-    pub fn record_request(&mut self, head: &ReqParts, body: Vec<u8>) {
+    pub fn record_request<B: Into<SpooledBody>>(
+        &mut self,
+        head: &ReqParts,
+        body: B,
+    ) -> io::Result<()> {
+        let body = body.into();
+        // Compressed request bodies are stored (and hashed) decoded so they can be
+        // read and matched regardless of how the client chose to compress them
+        let body = match ContentEncoding::from_headers(&head.headers) {
+            Some(enc) => body.decoded(enc).unwrap_or(body),
+            None => body,
+        };
         let mime_type = head
             .headers
             .get(header::CONTENT_TYPE)
@@ -83,10 +112,12 @@ impl HarSession {
             .map(|pq| format!("{}", pq))
             .unwrap_or_else(|| "".to_string());
         let http_version = convert::HttpVersion::har(head.version);
+        // Headers are left out, so ones that vary by client (like `X-Forwarded-For`)
+        // don't change which file a request is recorded to
         digest.input(&method);
         digest.input(&path_and_query);
         digest.input(&http_version);
-        digest.input(&body);
+        body.for_each(|piece| digest.input(piece))?;
         let request_hash = format!("{:x}", digest.result());
         let r = Request {
             method,
@@ -99,16 +130,27 @@ impl HarSession {
                 .map(|(k, v)| convert::Header::har(&k, &v))
                 .collect(),
             query_string: convert::Query::har(&head.uri.query()),
-            post_data: convert::RequestBody::har(body, mime_type.to_string()),
+            post_data: if body.is_empty()? {
+                None
+            } else {
+                let text = self.body_text(body)?;
+                Some(convert::RequestBody::har(text, mime_type.to_string()))
+            },
             headers_size: -1,
             body_size: -1,
             comment: Some(format!("hash:{}", &request_hash)),
         };
         self.request_hash = Some(request_hash);
         self.request = Some(r);
+        Ok(())
     }
 
-    pub fn record_response(&mut self, head: &ResParts, body: Vec<u8>) {
+    pub fn record_response<B: Into<SpooledBody>>(
+        &mut self,
+        head: &ResParts,
+        body: B,
+    ) -> io::Result<()> {
+        let body = body.into();
         let mime_type = head
             .headers
             .get(header::CONTENT_TYPE)
@@ -120,6 +162,20 @@ impl HarSession {
             .map(|v| v.to_str().unwrap_or(""))
             .unwrap_or("")
             .to_string();
+        let (body, compression) = match ContentEncoding::from_headers(&head.headers) {
+            Some(enc) => match body.decoded(enc) {
+                Ok(decoded) => {
+                    let saved = decoded.len()? as i64 - body.len()? as i64;
+                    (decoded, Some(saved))
+                }
+                Err(_) => (body, None),
+            },
+            None => (body, None),
+        };
+        let size = body.len()? as i64;
+        let text = self.body_text(body)?;
+        let mut content = convert::ResponseBody::har(size, text, mime_type.to_string());
+        content.compression = compression;
         let r = Response {
             charles_status: None,
             status: i64::from(head.status.as_u16()),
@@ -131,7 +187,7 @@ impl HarSession {
                 .iter()
                 .map(|(k, v)| convert::Header::har(k, v))
                 .collect(),
-            content: convert::ResponseBody::har(body, mime_type.to_string()),
+            content,
             redirect_url,
             headers_size: -1,
             body_size: -1,
@@ -139,6 +195,58 @@ impl HarSession {
         };
 
         self.response = Some(r);
+        Ok(())
+    }
+
+    /// The text of a body as the HAR stores it, and whether it's base64 encoded.  A
+    /// spooled body gets a placeholder instead, which `write_to_dir` swaps for the
+    /// contents of its file.
+    fn body_text(&mut self, body: SpooledBody) -> io::Result<(String, bool)> {
+        match body {
+            SpooledBody::Memory(b) => Ok(convert::maybe_encode(b)),
+            body => {
+                let base64 = !body.is_utf8()?;
+                let token: [u8; 8] = rand::random();
+                let token: String = token.iter().map(|b| format!("{:02x}", b)).collect();
+                let placeholder = format!("talkboy-spooled-body-{}", token);
+                self.spooled.push((placeholder.clone(), body, base64));
+                Ok((placeholder, base64))
+            }
+        }
+    }
+
+    /// Keep the arrival times of a streamed response body so it can be replayed with
+    /// the same shape
+    pub fn record_chunks(&mut self, chunks: Vec<ChunkTiming>) {
+        self.extensions.chunks = chunks;
+    }
+
+    /// How long the response body took to arrive, so its bandwidth can be played back
+    pub fn record_receive(&mut self, receive: Duration) {
+        self.receive = Some(receive);
+    }
+
+    pub fn record_websocket(&mut self, messages: Vec<WebSocketMessage>) {
+        self.extensions.websocket = messages;
+    }
+
+    pub fn record_trailers(&mut self, trailers: &HeaderMap) {
+        self.extensions.trailers = trailers
+            .iter()
+            .map(|(k, v)| convert::Header::har(k, v))
+            .collect();
+    }
+
+    pub fn record_grpc(&mut self, call: GrpcCall) {
+        self.extensions.grpc = Some(call);
+    }
+
+    pub fn record_route(&mut self, route: RouteTag) {
+        self.extensions.route = Some(route);
+    }
+
+    pub fn record_upstream_error(&mut self, error: String) {
+        self.extensions.upstream_error = Some(error);
     }
 
     fn build(&mut self) -> Result<Entries, IncompleteEntryError> {
@@ -154,10 +262,19 @@ impl HarSession {
             None => return Err(IncompleteEntryError::MissingStart),
         }
 
+        let time = (Utc::now() - self.start_date.unwrap()).num_milliseconds();
+        // Everything before the response body is counted as waiting for it
+        let (send, wait, receive) = match self.receive.take() {
+            Some(r) => {
+                let receive = r.as_millis() as i64;
+                (0, (time - receive).max(0), receive)
+            }
+            None => (-1, -1, -1),
+        };
         let entry = Entries {
             pageref: None,
             started_date_time: self.start_date.unwrap().to_rfc3339(),
-            time: (Utc::now() - self.start_date.unwrap()).num_milliseconds(),
+            time,
             request: self.request.take().unwrap(),
             response: self.response.take().unwrap(),
             cache: Cache {
@@ -168,15 +285,15 @@ impl HarSession {
                 blocked: None,
                 dns: None,
                 connect: None,
-                send: -1,
-                wait: -1,
-                receive: -1,
+                send,
+                wait,
+                receive,
                 ssl: None,
                 comment: None,
             },
             server_ip_address: None,
             connection: None,
-            comment: None,
+            comment: mem::take(&mut self.extensions).to_comment(),
         };
 
         Ok(entry)
@@ -217,10 +334,37 @@ impl HarSession {
             normalize_path(base_name.as_ref()),
             hash
         ));
-        let file = File::create(&file_name)?;
-        serde_json::to_writer_pretty(file, &self.har)?;
+        let mut file = BufWriter::new(File::create(&file_name)?);
+        if self.spooled.is_empty() {
+            serde_json::to_writer_pretty(&mut file, &self.har)?;
+        } else {
+            self.write_spooled(&mut file)?;
+        }
+        file.flush()?;
         Ok(file_name)
     }
+
+    /// Write the HAR with the spooled bodies streamed into the places held for them
+    fn write_spooled<W: Write>(&self, out: &mut W) -> Result<(), Error> {
+        let json = serde_json::to_string_pretty(&self.har)?;
+        let mut places: Vec<_> = self
+            .spooled
+            .iter()
+            .filter_map(|(placeholder, body, base64)| {
+                let at = json.find(&format!("\"{}\"", placeholder))? + 1;
+                Some((at, placeholder.len(), body, *base64))
+            })
+            .collect();
+        places.sort_by_key(|&(at, ..)| at);
+        let mut written = 0;
+        for (at, len, body, base64) in places {
+            out.write_all(&json.as_bytes()[written..at])?;
+            body.write_json(out, base64)?;
+            written = at + len;
+        }
+        out.write_all(&json.as_bytes()[written..])?;
+        Ok(())
+    }
 }
 
 #[derive(Debug, Fail)]
@@ -237,6 +381,34 @@ pub enum IncompleteEntryError {
     EmptySession,
 }
 
+/// Sets the path pattern of every entry in the HAR file at `path`, or removes it,
+/// leaving the rest of the file as it was.  Returns how many entries were changed.
+pub fn write_pattern(path: &Path, pattern: Option<&PathPattern>) -> Result<usize, Error> {
+    let mut har: Value = serde_json::from_reader(File::open(path)?)?;
+    let entries = har
+        .pointer_mut("/log/entries")
+        .and_then(Value::as_array_mut)
+        .ok_or(HarLoadingError::InvalidVersion)?;
+    for entry in entries.iter_mut() {
+        let comment = entry
+            .get("comment")
+            .and_then(Value::as_str)
+            .map(String::from);
+        let mut extensions = EntryExtensions::from_comment(&comment);
+        extensions.pattern = pattern.cloned();
+        match (extensions.to_comment(), entry.as_object_mut()) {
+            (Some(c), Some(e)) => e.insert("comment".to_string(), c.into()),
+            (None, Some(e)) => e.remove("comment"),
+            (_, None) => return Err(HarLoadingError::InvalidVersion.into()),
+        };
+    }
+    let changed = entries.len();
+    let mut file = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(&mut file, &har)?;
+    file.flush()?;
+    Ok(changed)
+}
+
 fn normalize_path(s: &str) -> String {
     lazy_static! {
         static ref PATTERN: Regex = Regex::new("[[:^word:]--\\.]").unwrap();
@@ -263,4 +435,89 @@ mod test {
         );
         assert_eq!("dots.ok", normalize_path("dots.ok"));
     }
+
+    #[test]
+    fn test_spooled_bodies_written_out() {
+        use super::{HarSession, SpooledBody};
+        use crate::archive::ContentEncoding;
+        use hyper::{Request, Response};
+        use serde_json::Value;
+        use std::io::Write;
+
+        let spool = |data: &[u8]| {
+            let mut f = tempfile::tempfile().unwrap();
+            f.write_all(data).unwrap();
+            SpooledBody::File(f)
+        };
+        // Starts a character before the end of the first piece read, and splits it
+        let mut text = "a".to_string();
+        text.push_str(&"é".repeat(40000));
+        text.push_str("\"quoted\"\n\u{1}");
+        let binary: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+
+        let (request, _) = Request::post("http://example.com/upload")
+            .header("content-encoding", "gzip")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let (response, _) = Response::builder().body(()).unwrap().into_parts();
+        let mut har = HarSession::new();
+        har.start_session();
+        let gzipped = ContentEncoding::Gzip.encode(text.as_bytes()).unwrap();
+        har.record_request(&request, spool(&gzipped)).unwrap();
+        har.record_response(&response, spool(&binary)).unwrap();
+        har.commit().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = har.write_to_dir(dir.path(), "upload").unwrap();
+
+        let written: Value = serde_json::from_reader(std::fs::File::open(path).unwrap()).unwrap();
+        let entry = &written["log"]["entries"][0];
+        assert_eq!(text, entry["request"]["postData"]["text"]);
+        assert_eq!(Value::Null, entry["request"]["postData"]["comment"]);
+        let content = &entry["response"]["content"];
+        assert_eq!("base64", content["encoding"]);
+        assert_eq!(200_000, content["size"]);
+        let decoded = base64::decode(content["text"].as_str().unwrap()).unwrap();
+        assert_eq!(binary, decoded);
+    }
+
+    #[test]
+    fn test_write_pattern() {
+        use super::{write_pattern, EntryExtensions, HarSession, PathPattern, RouteTag};
+        use hyper::{Request, Response};
+        use std::fs;
+
+        let (req, _) = Request::get("http://example.com/users/42")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let (res, _) = Response::new(()).into_parts();
+        let mut har = HarSession::new();
+        har.start_session();
+        har.record_route(RouteTag {
+            name: "users".to_string(),
+            path: None,
+        });
+        har.record_request(&req, Vec::new()).unwrap();
+        har.record_response(&res, b"found".to_vec()).unwrap();
+        har.commit().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = har.write_to_dir(dir.path(), "users").unwrap();
+        let extensions = || {
+            let har: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+            let comment = har["log"]["entries"][0]["comment"]
+                .as_str()
+                .map(String::from);
+            EntryExtensions::from_comment(&comment)
+        };
+
+        let users = PathPattern::Template("/users/{id}".to_string());
+        assert_eq!(1, write_pattern(&path, Some(&users)).unwrap());
+        assert_eq!(Some(users), extensions().pattern);
+        assert_eq!("users", extensions().route.unwrap().name);
+
+        write_pattern(&path, None).unwrap();
+        assert_eq!(None, extensions().pattern);
+        assert!(extensions().route.is_some());
+    }
 }
@@ -1,5 +1,7 @@
-use crate::archive::HarLoader;
+use crate::archive::{HarLoader, HarLoadingError, PathPattern};
 use crate::config::{Config, DelayOptions, PlaybackServerConfig, ProxyServerConfig};
+use crate::latency::{Bandwidth, Latency};
+use crate::status::StatusRange;
 use crate::VERSION;
 use clap::{App, AppSettings, Arg, ArgGroup, SubCommand};
 use failure::Error;
@@ -40,6 +42,11 @@ where
 pub enum CliConfig {
     Proxy(Vec<ProxyServerConfig>),
     Playback(Vec<PlaybackServerConfig>),
+    /// Set the path pattern of the recordings in `file`, or remove it
+    Pattern {
+        file: PathBuf,
+        pattern: Option<PathPattern>,
+    },
 }
 
 fn proxy_config_from_file(
@@ -59,7 +66,7 @@ fn proxy_config_from_cli(
     port: u16,
     project: &str,
     proxy_for: &str,
-    ignored_status_codes: Vec<u16>,
+    ignored_status_codes: Vec<StatusRange>,
 ) -> Result<Vec<ProxyServerConfig>, Error> {
     trace!(logger, "Creating Proxy config from CLI params");
     let socket_addr: SocketAddr = format!("{}:{}", addr, port).parse()?;
@@ -92,13 +99,15 @@ fn playback_config_from_cli(
     port: u16,
     project: &str,
     delay: DelayOptions,
+    bandwidth: Option<Bandwidth>,
 ) -> Result<Vec<PlaybackServerConfig>, Error> {
     trace!(logger, "Creating Playback config from CLI params");
     let socket_addr: SocketAddr = format!("{}:{}", addr, port).parse()?;
     let loader = HarLoader::new(logger);
     let p: PathBuf = PathBuf::from(&recording_dir).join(&project);
     let archives = loader.load_all(&p)?;
-    let s = PlaybackServerConfig::new(project, socket_addr, archives, delay);
+    let latency = Latency::new(rand::random(), delay).bandwidth(bandwidth);
+    let s = PlaybackServerConfig::new(project, socket_addr, archives, latency);
     Ok(vec![s])
 }
 
@@ -159,8 +168,8 @@ pub fn get_config(logger: Logger) -> Result<CliConfig, Error> {
                         .value_name("STATUS_CODES")
                         .use_delimiter(true)
                         .required(false)
-                        .help("Comma-delimited status codes to ignore and not record responses for")
-                        .validator(type_validator::<u16>),
+                        .help("Comma-delimited status codes (or classes like 5xx and ranges like 400-499) to ignore and not record responses for")
+                        .validator(type_validator::<StatusRange>),
                 )
                 .arg(
                     Arg::with_name("project_name")
@@ -182,7 +191,6 @@ pub fn get_config(logger: Logger) -> Result<CliConfig, Error> {
                 )
                 .group(
                     ArgGroup::with_name("from_cli")
-                        .arg("port")
                         .arg("project_name")
                         .arg("proxy_for")
                         .arg("ignored_status_codes")
@@ -193,7 +201,7 @@ pub fn get_config(logger: Logger) -> Result<CliConfig, Error> {
         .subcommand(
             SubCommand::with_name("playback")
                 .about("Start a server to play back recorded HTTP sessions")
-                .usage("talkboy playback [OPTIONS] (--config CONFIG | [DELAY_OPTION] [--addr ADDR] [--port PORT] PROJECT)")
+                .usage("talkboy playback [OPTIONS] (--config CONFIG | [DELAY_OPTION] [--bandwidth BYTES_PER_SEC] [--addr ADDR] [--port PORT] PROJECT)")
                 .arg(
                     Arg::with_name("config_file")
                         .short("c")
@@ -239,6 +247,17 @@ pub fn get_config(logger: Logger) -> Result<CliConfig, Error> {
                         .help("Introduce a static delay to each request")
                         .validator(type_validator::<u64>)
                 )
+                .arg(
+                    Arg::with_name("bandwidth")
+                        .short("b")
+                        .long("bandwidth")
+                        .value_name("BYTES_PER_SEC")
+                        .takes_value(true)
+                        .required(false)
+                        .conflicts_with("config_file")
+                        .help("Send bodies at most this fast, or \"recorded\" to match the recording")
+                        .validator(type_validator::<Bandwidth>)
+                )
                 .arg(
                     Arg::with_name("project_name")
                         .value_name("PROJECT")
@@ -252,7 +271,6 @@ pub fn get_config(logger: Logger) -> Result<CliConfig, Error> {
                 )
                 .group(
                     ArgGroup::with_name("from_cli")
-                        .arg("port")
                         .arg("project_name")
                         .multiple(true)
                         .conflicts_with("from_config"),
@@ -264,6 +282,46 @@ pub fn get_config(logger: Logger) -> Result<CliConfig, Error> {
                         .conflicts_with("from_config")
                         .requires("from_cli")
                 ),
+        )
+        .subcommand(
+            SubCommand::with_name("pattern")
+                .about("Make a recording answer a family of paths instead of only its own")
+                .usage("talkboy pattern [--regex] PROJECT FILE (PATTERN | --clear)")
+                .arg(
+                    Arg::with_name("regex")
+                        .short("r")
+                        .long("regex")
+                        .required(false)
+                        .help("PATTERN is a regex to match the whole path and query against"),
+                )
+                .arg(
+                    Arg::with_name("clear")
+                        .long("clear")
+                        .required(false)
+                        .conflicts_with_all(&["regex", "pattern"])
+                        .help("Remove the pattern, so only the recorded path is answered"),
+                )
+                .arg(
+                    Arg::with_name("project_name")
+                        .value_name("PROJECT")
+                        .help("Project name used to group HTTP sessions")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("file")
+                        .value_name("FILE")
+                        .help("Recording in the project's directory to change")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("pattern")
+                        .value_name("PATTERN")
+                        .help("Path and query with {name} placeholders, like /users/{id}")
+                        .required_unless("clear")
+                        .index(3),
+                ),
         );
     let matches = app.get_matches();
     println!("here");
@@ -273,16 +331,18 @@ pub fn get_config(logger: Logger) -> Result<CliConfig, Error> {
 
     if let Some(m) = matches.subcommand_matches("record") {
         let logger = logger.new(o!("config_for" => "proxy"));
-        let configs = if m.is_present("config") {
+        let configs = if m.is_present("config_file") {
             let logger = logger.new(o!("config_from" => "file"));
-            let file = m.value_of("config").unwrap();
+            let file = m.value_of("config_file").unwrap();
             proxy_config_from_file(logger, &file, &recording_dir)?
         } else {
             let logger = logger.new(o!("config_from" => "cli"));
             let addr = m.value_of("addr").expect("addr has a default");
             let port: u16 = m.value_of("port").expect("port has a default").parse()?;
-            let ignored_status_codes: Vec<u16> = match m.values_of("ignored_status_codes") {
-                Some(i) => i.map(|v| v.parse()).collect::<Result<Vec<u16>, _>>()?,
+            let ignored_status_codes = match m.values_of("ignored_status_codes") {
+                Some(i) => i
+                    .map(|v| v.parse())
+                    .collect::<Result<Vec<StatusRange>, _>>()?,
                 None => Vec::new(),
             };
             let project = m
@@ -303,9 +363,9 @@ pub fn get_config(logger: Logger) -> Result<CliConfig, Error> {
         Ok(CliConfig::Proxy(configs))
     } else if let Some(m) = matches.subcommand_matches("playback") {
         let logger = logger.new(o!("config_for" => "playback"));
-        let configs = if m.is_present("config") {
+        let configs = if m.is_present("config_file") {
             let logger = logger.new(o!("config_from" => "file"));
-            let file = m.value_of("config").unwrap();
+            let file = m.value_of("config_file").unwrap();
             playback_config_from_file(logger, &file, &recording_dir)?
         } else {
             let logger = logger.new(o!("config_from" => "cli"));
@@ -315,16 +375,45 @@ pub fn get_config(logger: Logger) -> Result<CliConfig, Error> {
                 .value_of("project_name")
                 .expect("project_name is required");
             let delay = if m.is_present("original_delay") {
-                DelayOptions::Original
+                DelayOptions::Original { scale: 1.0 }
             } else if m.is_present("delay_ms") {
                 let ms: u64 = m.value_of("delay_ms").unwrap().parse()?;
                 DelayOptions::Static { millis: ms }
             } else {
                 DelayOptions::None
             };
-            playback_config_from_cli(logger, &recording_dir, &addr, port, &project, delay)?
+            let bandwidth = m.value_of("bandwidth").map(|b| b.parse()).transpose()?;
+            playback_config_from_cli(
+                logger,
+                &recording_dir,
+                &addr,
+                port,
+                &project,
+                delay,
+                bandwidth,
+            )?
         };
         Ok(CliConfig::Playback(configs))
+    } else if let Some(m) = matches.subcommand_matches("pattern") {
+        let project = m.value_of("project_name").expect("PROJECT is required");
+        let file = m.value_of("file").expect("FILE is required");
+        let pattern = m.value_of("pattern").map(|p| {
+            if m.is_present("regex") {
+                PathPattern::Regex(p.to_string())
+            } else {
+                PathPattern::Template(p.to_string())
+            }
+        });
+        // Checked now rather than when the recording is next played back
+        if let Some(ref p) = pattern {
+            p.compile().map_err(|e| {
+                HarLoadingError::InvalidMatcher(format!("Invalid path pattern {:?}: {}", p, e))
+            })?;
+        }
+        Ok(CliConfig::Pattern {
+            file: PathBuf::from(&recording_dir).join(project).join(file),
+            pattern,
+        })
     } else {
         bail!("No recognized subcommand was provided; this should not occur")
     }
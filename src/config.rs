@@ -1,14 +1,38 @@
 use crate::archive::ArchivedRequest;
 use crate::archive::HarLoader;
+use crate::faults::{FaultRates, Faults};
+use crate::filter::{FilterRule, RecordFilter};
+use crate::grpc::Descriptors;
+use crate::latency::{Bandwidth, DelayRoute, Latency};
+use crate::playback::Fallback;
+use crate::proxy::Passthrough;
+use crate::rewrite::{self, OriginRewrite};
+use crate::route::{PathMatcher, Route};
+use crate::status::{self, StatusRange};
+use crate::tee::DEFAULT_SPOOL_THRESHOLD;
+use crate::tls::{self, TlsError};
+use crate::upstream::{UpstreamProxy, UpstreamProxyError};
+use crate::websocket::DEFAULT_MAX_FRAME_SIZE;
 use failure::Error;
-use hyper::Uri;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::server::Builder;
+use hyper::{StatusCode, Uri};
+use rand::distributions::{Distribution, LogNormal, Normal};
+use rand::Rng;
+use regex::Regex;
+use rustls::{ClientConfig, ServerConfig};
 use serde_derive::Deserialize;
 use slog::Logger;
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
+    /// Default for the projects that don't set their own
+    bandwidth: Option<Bandwidth>,
     #[serde(rename = "project")]
     projects: Vec<ProjectConfig>,
 }
@@ -18,34 +42,702 @@ pub struct ProjectConfig {
     name: String,
     addr: Option<String>,
     port: Option<u16>,
+    protocol: Option<Protocol>,
+    grpc: Option<GrpcConfig>,
+    tls: Option<TlsConfig>,
+    rewrite: Option<RewriteConfig>,
     playback: Option<PlaybackConfig>,
+    #[serde(alias = "proxy")]
     record: Option<ProxyConfig>,
 }
 
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+/// HTTP versions to speak, either when listening or when talking to the upstream
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// HTTP/1 plus HTTP/2 when the peer asks for it (prior knowledge or ALPN)
+    #[default]
+    Auto,
+    Http1,
+    Http2,
+}
+
+impl Protocol {
+    pub fn configure<I, E>(self, builder: Builder<I, E>) -> Builder<I, E> {
+        match self {
+            Protocol::Auto => builder,
+            Protocol::Http1 => builder.http1_only(true),
+            Protocol::Http2 => builder.http2_only(true),
+        }
+    }
+
+    /// ALPN protocol IDs to offer during a TLS handshake, most preferred first
+    pub fn alpn(self) -> Vec<Vec<u8>> {
+        let h2 = b"h2".to_vec();
+        let http1 = b"http/1.1".to_vec();
+        match self {
+            Protocol::Auto => vec![h2, http1],
+            Protocol::Http1 => vec![http1],
+            Protocol::Http2 => vec![h2],
+        }
+    }
+}
+
+/// What the recording proxy does with `Forwarded` and `X-Forwarded-*` headers
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardedHeaders {
+    /// Add the client's address, the listener's scheme and the `Host` the client
+    /// asked for, after whatever the client sent
+    Add,
+    /// Pass on whatever the client sent
+    #[default]
+    Preserve,
+    /// Remove them before the request goes upstream
+    Strip,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GrpcConfig {
+    /// Serialized `FileDescriptorSet` used to decode messages to JSON
+    descriptor_set: PathBuf,
+}
+
+impl GrpcConfig {
+    fn descriptors(&self) -> Result<Descriptors, Error> {
+        Descriptors::load(&self.descriptor_set)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TlsConfig {
+    /// PEM certificate chain; defaults to `<recording dir>/<project>.crt`
+    cert: Option<PathBuf>,
+    /// PEM private key; defaults to `<recording dir>/<project>.key`
+    key: Option<PathBuf>,
+    /// Hostnames to generate a self-signed certificate for, when `cert` doesn't exist
+    self_signed: Option<Vec<String>>,
+}
+
+impl TlsConfig {
+    fn server_config(
+        &self,
+        recording_dir: &Path,
+        name: &str,
+        protocol: Protocol,
+        logger: &Logger,
+    ) -> Result<Arc<ServerConfig>, Error> {
+        let cert = self
+            .cert
+            .clone()
+            .unwrap_or_else(|| recording_dir.join(format!("{}.crt", name)));
+        let key = self
+            .key
+            .clone()
+            .unwrap_or_else(|| recording_dir.join(format!("{}.key", name)));
+        if !cert.exists() || !key.exists() {
+            // Existing files are reused so clients only need to trust them once
+            let hostnames = self
+                .self_signed
+                .as_ref()
+                .ok_or(TlsError::MissingCertificate)?;
+            tls::write_self_signed(hostnames, &cert, &key)?;
+            info!(
+                logger,
+                "Wrote self-signed certificate to {}",
+                cert.display()
+            );
+        }
+        Ok(Arc::new(tls::server_config(&cert, &key, protocol)?))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RewriteConfig {
+    /// Response headers to rewrite; defaults to `Location`, `Content-Location` and `Link`
+    headers: Option<Vec<String>>,
+    /// Rewrite text bodies too
+    #[serde(default = "default_rewrite_bodies")]
+    bodies: bool,
+}
+
+fn default_rewrite_bodies() -> bool {
+    true
+}
+
+impl RewriteConfig {
+    fn origin_rewrite(self, tls: bool) -> Result<OriginRewrite, Error> {
+        let headers = match self.headers {
+            Some(h) => h.iter().map(|h| h.parse()).collect::<Result<_, _>>()?,
+            None => rewrite::DEFAULT_HEADERS
+                .iter()
+                .map(|h| HeaderName::from_static(h))
+                .collect(),
+        };
+        let scheme = if tls { "https" } else { "http" };
+        Ok(OriginRewrite::new(headers, self.bodies, scheme))
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
 #[serde(tag = "method")]
 pub enum DelayOptions {
     None,
-    Original,
-    Static { millis: u64 },
+    /// The recorded latency, multiplied by `scale`
+    Original {
+        #[serde(default = "default_delay_scale")]
+        scale: f64,
+    },
+    Static {
+        millis: u64,
+    },
+    /// Anywhere from `min_millis` to `max_millis`, all equally likely
+    Uniform {
+        min_millis: u64,
+        max_millis: u64,
+    },
+    Normal {
+        mean_millis: f64,
+        std_dev_millis: f64,
+    },
+    /// Mostly close to the median, with a long tail out to the 99th percentile
+    LogNormal {
+        p50_millis: f64,
+        p99_millis: f64,
+    },
+}
+
+fn default_delay_scale() -> f64 {
+    1.0
+}
+
+/// How many standard deviations above the mean the 99th percentile of a normal
+/// distribution is
+const Z_99: f64 = 2.326_348;
+
+#[derive(Debug, Fail)]
+pub enum DelayOptionsError {
+    #[fail(display = "Original delay needs a scale of at least 0")]
+    Scale,
+    #[fail(display = "Uniform delay needs min_millis no larger than max_millis")]
+    Uniform,
+    #[fail(display = "Normal delay needs a std_dev_millis of at least 0")]
+    Normal,
+    #[fail(display = "LogNormal delay needs 0 < p50_millis <= p99_millis")]
+    LogNormal,
+}
+
+impl DelayOptions {
+    pub fn check(&self) -> Result<(), DelayOptionsError> {
+        match *self {
+            DelayOptions::Original { scale } if scale.is_nan() || scale < 0.0 => {
+                Err(DelayOptionsError::Scale)
+            }
+            DelayOptions::Uniform {
+                min_millis,
+                max_millis,
+            } if min_millis > max_millis => Err(DelayOptionsError::Uniform),
+            DelayOptions::Normal { std_dev_millis, .. }
+                if std_dev_millis.is_nan() || std_dev_millis < 0.0 =>
+            {
+                Err(DelayOptionsError::Normal)
+            }
+            DelayOptions::LogNormal {
+                p50_millis,
+                p99_millis,
+            } if !(p50_millis > 0.0 && p50_millis <= p99_millis) => {
+                Err(DelayOptionsError::LogNormal)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the delay is drawn at random
+    pub fn is_random(&self) -> bool {
+        matches!(
+            self,
+            DelayOptions::Uniform { .. }
+                | DelayOptions::Normal { .. }
+                | DelayOptions::LogNormal { .. }
+        )
+    }
+
+    /// Draw a delay, for the options that don't depend on the recording
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> Duration {
+        let millis = match *self {
+            DelayOptions::None | DelayOptions::Original { .. } => 0.0,
+            DelayOptions::Static { millis } => millis as f64,
+            DelayOptions::Uniform {
+                min_millis,
+                max_millis,
+            } => rng.gen_range(min_millis, max_millis + 1) as f64,
+            DelayOptions::Normal {
+                mean_millis,
+                std_dev_millis,
+            } => Normal::new(mean_millis, std_dev_millis).sample(rng),
+            DelayOptions::LogNormal {
+                p50_millis,
+                p99_millis,
+            } => {
+                let mu = p50_millis.ln();
+                let sigma = (p99_millis.ln() - mu) / Z_99;
+                LogNormal::new(mu, sigma).sample(rng)
+            }
+        };
+        // A normal distribution can go below zero
+        Duration::from_micros((millis.max(0.0) * 1000.0) as u64)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+pub struct WebSocketOptions {
+    /// Wait for the client to send each recorded client frame before replaying the
+    /// server frames that followed it
+    #[serde(default)]
+    pub wait_for_client: bool,
+    /// Largest frame payload accepted from the client
+    pub max_frame_size: Option<usize>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct PlaybackConfig {
     delay: Option<DelayOptions>,
+    /// Seeds the random delays; random itself when absent
+    delay_seed: Option<u64>,
+    /// Delays for matching requests, used instead of `delay`
+    #[serde(default)]
+    delays: Vec<DelayRouteConfig>,
+    bandwidth: Option<Bandwidth>,
+    websocket: Option<WebSocketOptions>,
+    /// Serve the responses recording quarantined too
+    #[serde(default)]
+    include_ignored: bool,
+    /// Forwarded upstream instead of played back
+    #[serde(default)]
+    passthrough: Vec<RouteConfig>,
+    /// Answers the requests no recording matches
+    fallback: Option<FallbackConfig>,
+    faults: Option<FaultsConfig>,
+    /// Shift recorded dates by how long ago they were recorded
+    #[serde(default)]
+    refresh_dates: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DelayRouteConfig {
+    /// Any method when empty
+    #[serde(default)]
+    methods: Vec<String>,
+    /// Any path without a `prefix` or `regex`
+    prefix: Option<String>,
+    regex: Option<String>,
+    delay: Option<DelayOptions>,
+    bandwidth: Option<Bandwidth>,
+}
+
+impl DelayRouteConfig {
+    fn route(self) -> Result<DelayRoute, Error> {
+        let matcher = match (self.prefix, self.regex) {
+            (Some(p), None) => Some(PathMatcher::prefix(p)),
+            (None, Some(r)) => Some(PathMatcher::regex(&r)?),
+            (None, None) => None,
+            (Some(_), Some(_)) => return Err(RouteConfigError::BothMatchers.into()),
+        };
+        if let Some(d) = self.delay {
+            d.check()?;
+        }
+        let methods = self
+            .methods
+            .iter()
+            .map(|m| m.to_uppercase().parse())
+            .collect::<Result<_, _>>()?;
+        Ok(DelayRoute::new(
+            methods,
+            matcher,
+            self.delay,
+            self.bandwidth,
+        ))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FaultRatesConfig {
+    #[serde(default)]
+    error: f64,
+    error_status: Option<u16>,
+    #[serde(default)]
+    reset: f64,
+    #[serde(default)]
+    stall: f64,
+    /// Stall forever when absent
+    stall_millis: Option<u64>,
+    #[serde(default)]
+    truncate: f64,
+    #[serde(default)]
+    malformed_chunked: f64,
+}
+
+impl FaultRatesConfig {
+    fn rates(self) -> Result<FaultRates, Error> {
+        let defaults = FaultRates::default();
+        let rates = FaultRates {
+            error: self.error,
+            error_status: match self.error_status {
+                Some(s) => StatusCode::from_u16(s)?,
+                None => defaults.error_status,
+            },
+            reset: self.reset,
+            stall: self.stall,
+            stall_for: self.stall_millis.map(Duration::from_millis),
+            truncate: self.truncate,
+            malformed_chunked: self.malformed_chunked,
+        };
+        rates.check()?;
+        Ok(rates)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FaultRouteConfig {
+    prefix: Option<String>,
+    regex: Option<String>,
+    #[serde(flatten)]
+    rates: FaultRatesConfig,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FaultsConfig {
+    /// Random when absent
+    seed: Option<u64>,
+    #[serde(flatten)]
+    rates: FaultRatesConfig,
+    /// Rates for matching paths, used instead of the global ones
+    #[serde(default)]
+    routes: Vec<FaultRouteConfig>,
+}
+
+impl FaultsConfig {
+    fn faults(self) -> Result<Faults, Error> {
+        let seed = self.seed.unwrap_or_else(rand::random);
+        let mut faults = Faults::new(seed, self.rates.rates()?);
+        for r in self.routes {
+            let matcher = match (r.prefix, r.regex) {
+                (Some(p), None) => PathMatcher::prefix(p),
+                (None, Some(r)) => PathMatcher::regex(&r)?,
+                _ => return Err(RouteConfigError::NoFaultMatcher.into()),
+            };
+            faults = faults.route(matcher, r.rates.rates()?);
+        }
+        Ok(faults)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action")]
+pub enum FallbackConfig {
+    NotFound,
+    Respond {
+        status: u16,
+        #[serde(default)]
+        body: String,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+    },
+    /// A HAR file, relative to the project's recordings
+    Recording {
+        file: String,
+    },
+    Close,
+    Forward {
+        uri: String,
+    },
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "Fallback recording {} has no entries", _0)]
+pub struct EmptyFallbackError(String);
+
+impl FallbackConfig {
+    fn fallback(
+        self,
+        project_dir: &Path,
+        loader: &HarLoader,
+        upstream: &ProxyServerConfig,
+    ) -> Result<Fallback, Error> {
+        Ok(match self {
+            FallbackConfig::NotFound => Fallback::NotFound,
+            FallbackConfig::Respond {
+                status,
+                body,
+                headers,
+            } => {
+                let mut map = HeaderMap::new();
+                for (name, value) in headers {
+                    map.append(
+                        HeaderName::from_bytes(name.as_bytes())?,
+                        HeaderValue::from_str(&value)?,
+                    );
+                }
+                Fallback::Respond {
+                    status: StatusCode::from_u16(status)?,
+                    headers: map,
+                    body: body.into(),
+                }
+            }
+            FallbackConfig::Recording { file } => {
+                let path = project_dir.join(&file);
+                match loader.load(&path)?.into_iter().next() {
+                    Some(r) => Fallback::Recording(Box::new(r)),
+                    None => return Err(EmptyFallbackError(file).into()),
+                }
+            }
+            FallbackConfig::Close => Fallback::Close,
+            FallbackConfig::Forward { uri } => {
+                let route = Route::new("fallback", PathMatcher::prefix("/"), uri.parse()?)?;
+                Fallback::Forward(Box::new(Passthrough::new(vec![route], upstream)))
+            }
+        })
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ProxyConfig {
     uri: String,
-    ignored_status_codes: Option<Vec<u16>>,
+    ignored_status_codes: Option<Vec<StatusRange>>,
+    /// Only record responses with these statuses
+    recorded_status_codes: Option<Vec<StatusRange>>,
+    /// Write ignored responses to `_ignored/` instead of dropping them
+    #[serde(default)]
+    quarantine_ignored: bool,
+    spool_threshold: Option<usize>,
+    /// Largest WebSocket frame payload relayed in either direction
+    max_frame_size: Option<usize>,
+    upstream_protocol: Option<Protocol>,
+    forwarded_headers: Option<ForwardedHeaders>,
+    /// Give up connecting to the upstream (including any proxy and TLS) after this long
+    connect_timeout_millis: Option<u64>,
+    /// Give up waiting for the upstream's response headers after this long
+    request_timeout_millis: Option<u64>,
+    /// Extra attempts after a failed connection or timeout
+    retries: Option<u32>,
+    /// Record a made-up 502 or 504 when the upstream can't be reached
+    #[serde(default)]
+    record_failures: bool,
+    tls: Option<UpstreamTlsConfig>,
+    upstream_proxy: Option<UpstreamProxyConfig>,
+    #[serde(default)]
+    routes: Vec<RouteConfig>,
+    /// Only record exchanges matching one of these
+    #[serde(default)]
+    include: Vec<FilterRuleConfig>,
+    /// Never record exchanges matching one of these
+    #[serde(default)]
+    exclude: Vec<FilterRuleConfig>,
+}
+
+impl ProxyConfig {
+    /// The recording proxy, without the settings that belong to the whole project
+    fn server(
+        self,
+        name: String,
+        socket: SocketAddr,
+        recording_dir: &str,
+        logger: &Logger,
+    ) -> Result<ProxyServerConfig, Error> {
+        let uri: Uri = self.uri.parse()?;
+        let mut server = ProxyServerConfig::new(
+            name,
+            socket,
+            uri,
+            recording_dir,
+            self.ignored_status_codes.unwrap_or_default(),
+        );
+        server.recorded_status_codes = self.recorded_status_codes;
+        server.quarantine_ignored = self.quarantine_ignored;
+        if let Some(t) = self.spool_threshold {
+            server.spool_threshold = t;
+        }
+        if let Some(m) = self.max_frame_size {
+            server.max_frame_size = m;
+        }
+        server.upstream_protocol = self.upstream_protocol.unwrap_or_default();
+        server.forwarded_headers = self.forwarded_headers.unwrap_or_default();
+        server.connect_timeout = self.connect_timeout_millis.map(Duration::from_millis);
+        server.request_timeout = self.request_timeout_millis.map(Duration::from_millis);
+        server.retries = self.retries.unwrap_or(0);
+        server.record_failures = self.record_failures;
+        if let Some(t) = self.tls {
+            server.upstream_tls = t.client_config(logger)?;
+            server.server_name = t.server_name;
+            server.insecure = t.insecure;
+        }
+        if let Some(u) = self.upstream_proxy {
+            server.upstream_proxy = u.upstream_proxy(&server.proxy_for)?;
+        }
+        let rules = |rules: Vec<FilterRuleConfig>| {
+            rules
+                .into_iter()
+                .map(FilterRuleConfig::rule)
+                .collect::<Result<Vec<_>, _>>()
+        };
+        server.filter = RecordFilter::new(rules(self.include)?, rules(self.exclude)?);
+        server.routes = self
+            .routes
+            .into_iter()
+            .map(RouteConfig::route)
+            .collect::<Result<_, _>>()?;
+        Ok(server)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FilterRuleConfig {
+    #[serde(default)]
+    methods: Vec<String>,
+    /// Glob matched against the whole path
+    path: Option<String>,
+    /// Matched anywhere in the path, unless anchored
+    regex: Option<String>,
+    #[serde(default)]
+    content_types: Vec<String>,
+    /// Request headers that have to be present
+    #[serde(default)]
+    headers: Vec<String>,
+}
+
+impl FilterRuleConfig {
+    fn rule(self) -> Result<FilterRule, Error> {
+        let mut paths = Vec::new();
+        if let Some(p) = self.path {
+            paths.push(FilterRule::glob(&p)?);
+        }
+        if let Some(r) = self.regex {
+            paths.push(Regex::new(&r)?);
+        }
+        Ok(FilterRule {
+            methods: self
+                .methods
+                .iter()
+                .map(|m| m.to_uppercase().parse())
+                .collect::<Result<_, _>>()?,
+            paths,
+            content_types: self.content_types,
+            headers: self
+                .headers
+                .iter()
+                .map(|h| h.parse())
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RouteConfig {
+    /// Tags the recordings of the route; defaults to `prefix` or `regex`
+    name: Option<String>,
+    prefix: Option<String>,
+    regex: Option<String>,
+    uri: String,
+    /// Remove the matched part of the path before sending the request upstream
+    #[serde(default)]
+    strip_prefix: bool,
+}
+
+#[derive(Debug, Fail)]
+pub enum RouteConfigError {
+    #[fail(display = "Route to {} needs exactly one of prefix or regex", _0)]
+    NoMatcher(String),
+    #[fail(display = "Fault route needs exactly one of prefix or regex")]
+    NoFaultMatcher,
+    #[fail(display = "Delay route can't have both a prefix and a regex")]
+    BothMatchers,
+}
+
+impl RouteConfig {
+    fn route(self) -> Result<Route, Error> {
+        let (name, matcher) = match (self.prefix, self.regex) {
+            (Some(p), None) => (p.clone(), PathMatcher::prefix(p)),
+            (None, Some(r)) => (r.clone(), PathMatcher::regex(&r)?),
+            _ => return Err(RouteConfigError::NoMatcher(self.uri).into()),
+        };
+        let mut route = Route::new(self.name.unwrap_or(name), matcher, self.uri.parse()?)?;
+        route.strip_prefix = self.strip_prefix;
+        Ok(route)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpstreamProxyConfig {
+    /// `http://[user:password@]host:port`, with the credentials percent-encoded
+    uri: Option<String>,
+    /// Without `uri`, use `HTTPS_PROXY` or `HTTP_PROXY` and `NO_PROXY`
+    #[serde(default)]
+    from_env: bool,
+    /// Hosts (and their subdomains) to connect to directly
+    #[serde(default)]
+    no_proxy: Vec<String>,
+}
+
+impl UpstreamProxyConfig {
+    fn upstream_proxy(self, upstream: &Uri) -> Result<Option<UpstreamProxy>, Error> {
+        match self.uri {
+            Some(uri) => Ok(Some(UpstreamProxy::new(&uri, self.no_proxy)?)),
+            None if self.from_env => {
+                UpstreamProxy::from_env(upstream.scheme_str().unwrap_or("http"), self.no_proxy)
+            }
+            None => Err(UpstreamProxyError::MissingUri.into()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpstreamTlsConfig {
+    /// PEM files of CA certificates to trust besides the usual roots
+    #[serde(default)]
+    ca_certs: Vec<PathBuf>,
+    /// PEM certificate chain and private key to present for mutual TLS
+    client_cert: Option<PathBuf>,
+    client_key: Option<PathBuf>,
+    /// Name to send as SNI and verify the certificate against, instead of the host
+    server_name: Option<String>,
+    /// Accept any certificate the upstream presents
+    #[serde(default)]
+    insecure: bool,
+}
+
+impl UpstreamTlsConfig {
+    fn client_config(&self, logger: &Logger) -> Result<ClientConfig, Error> {
+        let client_cert = match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => Some((cert.as_path(), key.as_path())),
+            (Some(_), None) => return Err(TlsError::MissingClientKey.into()),
+            (None, Some(_)) => return Err(TlsError::MissingClientCert.into()),
+            (None, None) => None,
+        };
+        if self.insecure {
+            warn!(logger, "Not verifying upstream TLS certificates");
+        }
+        tls::client_config(&self.ca_certs, client_cert, self.insecure)
+    }
 }
 
 pub struct PlaybackServerConfig {
     pub name: String,
     pub socket: SocketAddr,
     pub archives: Vec<ArchivedRequest>,
-    pub delay: DelayOptions,
+    pub latency: Latency,
+    pub websocket: WebSocketOptions,
+    pub protocol: Protocol,
+    pub descriptors: Option<Descriptors>,
+    pub tls: Option<Arc<ServerConfig>>,
+    /// Points the recorded upstream's URLs at the listener
+    pub rewrite: Option<OriginRewrite>,
+    /// Forwards some routes upstream instead of playing them back
+    pub passthrough: Option<Passthrough>,
+    /// Answers the requests no recording matches
+    pub fallback: Fallback,
+    pub faults: Option<Faults>,
+    /// Shift recorded dates to make responses look fresh
+    pub refresh_dates: bool,
 }
 
 impl PlaybackServerConfig {
@@ -53,13 +745,22 @@ impl PlaybackServerConfig {
         name: S,
         socket: SocketAddr,
         archives: Vec<ArchivedRequest>,
-        delay: DelayOptions,
+        latency: Latency,
     ) -> PlaybackServerConfig {
         PlaybackServerConfig {
             name: name.into(),
             socket,
             archives,
-            delay,
+            latency,
+            websocket: WebSocketOptions::default(),
+            protocol: Protocol::default(),
+            descriptors: None,
+            tls: None,
+            rewrite: None,
+            passthrough: None,
+            fallback: Fallback::default(),
+            faults: None,
+            refresh_dates: false,
         }
     }
 }
@@ -69,7 +770,32 @@ pub struct ProxyServerConfig {
     pub socket: SocketAddr,
     pub archive_path: PathBuf,
     pub proxy_for: Uri,
-    pub ignored_status_codes: Vec<u16>,
+    pub ignored_status_codes: Vec<StatusRange>,
+    pub recorded_status_codes: Option<Vec<StatusRange>>,
+    pub quarantine_ignored: bool,
+    /// Which of the exchanges that aren't ignored get recorded
+    pub filter: RecordFilter,
+    pub spool_threshold: usize,
+    pub max_frame_size: usize,
+    pub protocol: Protocol,
+    pub upstream_protocol: Protocol,
+    pub forwarded_headers: ForwardedHeaders,
+    pub connect_timeout: Option<Duration>,
+    pub request_timeout: Option<Duration>,
+    pub retries: u32,
+    pub record_failures: bool,
+    pub upstream_tls: ClientConfig,
+    /// Overrides the upstream host as the TLS server name
+    pub server_name: Option<String>,
+    /// Upstream certificates aren't verified
+    pub insecure: bool,
+    pub upstream_proxy: Option<UpstreamProxy>,
+    /// Checked in order before falling back to `proxy_for`
+    pub routes: Vec<Route>,
+    pub descriptors: Option<Descriptors>,
+    pub tls: Option<Arc<ServerConfig>>,
+    /// Points the upstream's URLs at the listener
+    pub rewrite: Option<OriginRewrite>,
 }
 
 impl ProxyServerConfig {
@@ -78,7 +804,7 @@ impl ProxyServerConfig {
         socket: SocketAddr,
         proxy_for: Uri,
         archive_path: P,
-        ignored_status_codes: Vec<u16>,
+        ignored_status_codes: Vec<StatusRange>,
     ) -> ProxyServerConfig {
         ProxyServerConfig {
             name: name.into(),
@@ -86,10 +812,41 @@ impl ProxyServerConfig {
             proxy_for,
             archive_path: archive_path.into(),
             ignored_status_codes,
+            recorded_status_codes: None,
+            quarantine_ignored: false,
+            filter: RecordFilter::default(),
+            spool_threshold: DEFAULT_SPOOL_THRESHOLD,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            protocol: Protocol::default(),
+            upstream_protocol: Protocol::default(),
+            forwarded_headers: ForwardedHeaders::default(),
+            connect_timeout: None,
+            request_timeout: None,
+            retries: 0,
+            record_failures: false,
+            upstream_tls: tls::default_client_config(),
+            server_name: None,
+            insecure: false,
+            upstream_proxy: None,
+            routes: Vec::new(),
+            descriptors: None,
+            tls: None,
+            rewrite: None,
         }
     }
 }
 
+impl ProxyServerConfig {
+    /// Whether responses with `status` are left out of the recordings
+    pub fn is_ignored(&self, status: u16) -> bool {
+        status::is_ignored(
+            status,
+            &self.ignored_status_codes,
+            self.recorded_status_codes.as_deref(),
+        )
+    }
+}
+
 struct NextUnusedPort {
     current: u16,
     used: Vec<u16>,
@@ -149,14 +906,20 @@ impl Config {
 
                 let socket_addr: SocketAddr = format!("{}:{}", addr, port).parse()?;
                 let proxy = p.record.unwrap();
-                let uri: Uri = proxy.uri.parse()?;
-                Ok(ProxyServerConfig::new(
-                    p.name,
-                    socket_addr,
-                    uri,
-                    &recording_dir,
-                    proxy.ignored_status_codes.unwrap_or_else(Vec::new),
-                ))
+                let mut server = proxy.server(p.name, socket_addr, recording_dir, &logger)?;
+                server.protocol = p.protocol.unwrap_or_default();
+                if let Some(g) = p.grpc {
+                    server.descriptors = Some(g.descriptors()?);
+                }
+                if let Some(r) = p.rewrite {
+                    server.rewrite = Some(r.origin_rewrite(p.tls.is_some())?);
+                }
+                if let Some(t) = p.tls {
+                    let dir = Path::new(recording_dir);
+                    let name = &server.name;
+                    server.tls = Some(t.server_config(dir, name, server.protocol, &logger)?);
+                }
+                Ok(server)
             })
             .collect::<Result<Vec<ProxyServerConfig>, Error>>()
     }
@@ -167,35 +930,88 @@ impl Config {
         logger: Logger,
     ) -> Result<Vec<PlaybackServerConfig>, Error> {
         let logger = &logger;
+        let bandwidth = self.bandwidth;
         let mut next_port = NextUnusedPort::new(8080);
         self.projects
             .into_iter()
             .filter(|p| p.playback.is_some())
-            .map(|p| {
-                let addr = p.addr.unwrap_or_else(|| "127.0.0.1".to_string());
-                let port = p.port.map(|p| next_port.observe(p)).unwrap_or_else(|| {
-                    next_port
-                        .next()
-                        .expect("Ran out of ports trying to assign for playback")
-                });
-                let playback = p.playback.unwrap();
-                (addr, port, p.name, playback)
-            })
-            .map(move |(addr, port, name, playback)| {
+            .map(move |project| {
+                let addr = project.addr.unwrap_or_else(|| "127.0.0.1".to_string());
+                let port = project
+                    .port
+                    .map(|p| next_port.observe(p))
+                    .unwrap_or_else(|| {
+                        next_port
+                            .next()
+                            .expect("Ran out of ports trying to assign for playback")
+                    });
+                let playback = project.playback.unwrap();
+                let protocol = project.protocol.unwrap_or_default();
                 let socket_addr: SocketAddr = format!("{}:{}", addr, port).parse()?;
                 let delay = playback.delay.unwrap_or(DelayOptions::None);
-                let logger = logger.new(o!("loader" => "HarLoader"));
-                let loader = HarLoader::new(logger);
+                delay.check()?;
+                let seed = playback.delay_seed.unwrap_or_else(rand::random);
+                let mut latency =
+                    Latency::new(seed, delay).bandwidth(playback.bandwidth.or(bandwidth));
+                for r in playback.delays {
+                    latency = latency.route(r.route()?);
+                }
+                let loader = HarLoader::new(logger.new(o!("loader" => "HarLoader")))
+                    .decode_grpc(project.grpc.is_some())
+                    .include_ignored(playback.include_ignored);
                 let p: PathBuf = recording_dir.into();
-                let p = p.join(&name);
+                let p = p.join(&project.name);
                 let archives = loader.load_all(&p)?;
 
-                Ok(PlaybackServerConfig::new(
-                    name,
-                    socket_addr,
-                    archives,
-                    delay,
-                ))
+                let routes = playback
+                    .passthrough
+                    .into_iter()
+                    .map(RouteConfig::route)
+                    .collect::<Result<Vec<_>, _>>()?;
+                // Passthrough routes and a forwarding fallback reach their upstreams the
+                // way recording would.  The recording settings aren't needed, or checked,
+                // when nothing is forwarded.
+                let forwards = !routes.is_empty()
+                    || matches!(playback.fallback, Some(FallbackConfig::Forward { .. }));
+                let upstream = match project.record {
+                    Some(r) if forwards => {
+                        r.server(project.name.clone(), socket_addr, recording_dir, logger)?
+                    }
+                    _ => ProxyServerConfig::new(
+                        project.name.clone(),
+                        socket_addr,
+                        Uri::default(),
+                        recording_dir,
+                        Vec::new(),
+                    ),
+                };
+                let mut server =
+                    PlaybackServerConfig::new(project.name, socket_addr, archives, latency);
+                if let Some(ws) = playback.websocket {
+                    server.websocket = ws;
+                }
+                if !routes.is_empty() {
+                    server.passthrough = Some(Passthrough::new(routes, &upstream));
+                }
+                if let Some(f) = playback.fallback {
+                    server.fallback = f.fallback(&p, &loader, &upstream)?;
+                }
+                if let Some(f) = playback.faults {
+                    server.faults = Some(f.faults()?);
+                }
+                server.refresh_dates = playback.refresh_dates;
+                server.protocol = protocol;
+                if let Some(g) = project.grpc {
+                    server.descriptors = Some(g.descriptors()?);
+                }
+                if let Some(r) = project.rewrite {
+                    server.rewrite = Some(r.origin_rewrite(project.tls.is_some())?);
+                }
+                if let Some(t) = project.tls {
+                    let dir = Path::new(recording_dir);
+                    server.tls = Some(t.server_config(dir, &server.name, protocol, logger)?);
+                }
+                Ok(server)
             })
             .collect::<Result<Vec<PlaybackServerConfig>, Error>>()
     }
@@ -203,7 +1019,11 @@ impl Config {
 
 #[cfg(test)]
 mod test {
-    use super::Config;
+    use super::{Config, DelayOptions, FallbackConfig, UpstreamTlsConfig};
+    use crate::latency::Bandwidth;
+    use crate::status::StatusRange;
+    use slog::{Discard, Logger};
+    use std::fs;
     use toml;
     #[test]
     fn test_parse_config() {
@@ -235,4 +1055,224 @@ ignored_status_codes = [ 500 ]
             Err(e) => assert!(false, "Didn't parse correctly: {}", e),
         }
     }
+
+    #[test]
+    fn test_proxy_section_alias() {
+        let conf = r#"
+[[project]]
+name = "foo"
+
+[project.proxy]
+uri = "https://www.google.com"
+"#;
+        let c: Config = toml::from_str(conf).unwrap();
+        assert!(c.projects[0].record.is_some());
+    }
+
+    #[test]
+    fn test_status_codes() {
+        let conf = r#"
+[[project]]
+name = "foo"
+
+[project.proxy]
+uri = "https://www.google.com"
+ignored_status_codes = [ "500", "4xx" ]
+recorded_status_codes = [ "2xx", "304-399" ]
+quarantine_ignored = true
+"#;
+        let c: Config = toml::from_str(conf).unwrap();
+        let proxy = c.projects[0].record.as_ref().unwrap();
+        let ranges =
+            |r: &[&str]| -> Vec<StatusRange> { r.iter().map(|r| r.parse().unwrap()).collect() };
+        assert_eq!(
+            Some(ranges(&["500", "400-499"])),
+            proxy.ignored_status_codes
+        );
+        assert_eq!(
+            Some(ranges(&["200-299", "304-399"])),
+            proxy.recorded_status_codes
+        );
+        assert!(proxy.quarantine_ignored);
+    }
+
+    #[test]
+    fn test_bad_status_class() {
+        let conf = r#"
+[[project]]
+name = "foo"
+
+[project.proxy]
+uri = "https://www.google.com"
+ignored_status_codes = [ "5x" ]
+"#;
+        assert!(toml::from_str::<Config>(conf).is_err());
+    }
+
+    #[test]
+    fn test_playback_only_reads_record_settings_to_forward() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("foo")).unwrap();
+        let recording_dir = dir.path().to_str().unwrap();
+        let logger = Logger::root(Discard, o!());
+        let conf = r#"
+[[project]]
+name = "foo"
+
+[project.playback]
+
+[project.proxy]
+uri = "https://www.google.com"
+tls = { client_key = "client.key" }
+"#;
+        let c: Config = toml::from_str(conf).unwrap();
+        let servers = c.try_into_playback_servers(recording_dir, logger.clone());
+        assert!(servers.unwrap()[0].passthrough.is_none());
+
+        let forwarding = [
+            "passthrough = [ { prefix = \"/api\", uri = \"http://localhost:9000\" } ]",
+            "fallback = { action = \"Forward\", uri = \"http://localhost:9000\" }",
+        ];
+        for f in &forwarding {
+            let conf = conf.replace("[project.playback]", &format!("[project.playback]\n{}", f));
+            let c: Config = toml::from_str(&conf).unwrap();
+            assert!(c
+                .try_into_playback_servers(recording_dir, logger.clone())
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_delays() {
+        let conf = r#"
+[[project]]
+name = "foo"
+
+[project.playback]
+delay = { method = "Uniform", min_millis = 10, max_millis = 50 }
+delay_seed = 42
+delays = [
+    { methods = [ "post" ], prefix = "/api", delay = { method = "Static", millis = 500 } },
+    { regex = "^/slow/", delay = { method = "Original" } },
+]
+"#;
+        let c: Config = toml::from_str(conf).unwrap();
+        let playback = c.projects[0].playback.as_ref().unwrap();
+        let uniform = DelayOptions::Uniform {
+            min_millis: 10,
+            max_millis: 50,
+        };
+        assert_eq!(Some(uniform), playback.delay);
+        assert_eq!(Some(42), playback.delay_seed);
+        let api = &playback.delays[0];
+        assert_eq!(vec!["post"], api.methods);
+        assert_eq!(
+            (Some("/api"), None),
+            (api.prefix.as_deref(), api.regex.as_deref())
+        );
+        assert_eq!(Some(DelayOptions::Static { millis: 500 }), api.delay);
+        let slow = &playback.delays[1];
+        assert!(slow.methods.is_empty());
+        assert_eq!(Some("^/slow/"), slow.regex.as_deref());
+        assert_eq!(Some(DelayOptions::Original { scale: 1.0 }), slow.delay);
+    }
+
+    #[test]
+    fn test_bandwidth() {
+        let conf = r#"
+bandwidth = 65536
+
+[[project]]
+name = "foo"
+
+[project.playback]
+bandwidth = "recorded"
+delays = [ { prefix = "/downloads", bandwidth = 1024 } ]
+
+[[project]]
+name = "bar"
+
+[project.playback]
+"#;
+        let c: Config = toml::from_str(conf).unwrap();
+        assert_eq!(Some(Bandwidth::Limit(65536)), c.bandwidth);
+        let playback = |i: usize| c.projects[i].playback.as_ref().unwrap();
+        assert_eq!(Some(Bandwidth::Recorded), playback(0).bandwidth);
+        let downloads = &playback(0).delays[0];
+        assert_eq!(None, downloads.delay);
+        assert_eq!(Some(Bandwidth::Limit(1024)), downloads.bandwidth);
+        assert_eq!(None, playback(1).bandwidth);
+
+        assert!(toml::from_str::<Config>(r#"bandwidth = "fast""#).is_err());
+    }
+
+    #[test]
+    fn test_refresh_dates() {
+        let conf = r#"
+[[project]]
+name = "foo"
+
+[project.playback]
+refresh_dates = true
+
+[[project]]
+name = "bar"
+
+[project.playback]
+"#;
+        let c: Config = toml::from_str(conf).unwrap();
+        let refresh_dates = |i: usize| c.projects[i].playback.as_ref().unwrap().refresh_dates;
+        assert!(refresh_dates(0));
+        assert!(!refresh_dates(1));
+    }
+
+    #[test]
+    fn test_fallback() {
+        let conf = r#"
+[[project]]
+name = "foo"
+
+[project.playback]
+fallback = { action = "Respond", status = 501, body = "No recording" }
+
+[[project]]
+name = "bar"
+
+[project.playback]
+fallback = { action = "Forward", uri = "http://localhost:9000" }
+"#;
+        let c: Config = toml::from_str(conf).unwrap();
+        let fallback = |i: usize| c.projects[i].playback.as_ref().unwrap().fallback.as_ref();
+        match fallback(0) {
+            Some(FallbackConfig::Respond { status, body, .. }) => {
+                assert_eq!((501, "No recording"), (*status, body.as_str()))
+            }
+            f => panic!("Parsed {:?}", f),
+        }
+        match fallback(1) {
+            Some(FallbackConfig::Forward { uri }) => assert_eq!("http://localhost:9000", uri),
+            f => panic!("Parsed {:?}", f),
+        }
+    }
+
+    #[test]
+    fn test_unknown_fallback() {
+        let conf = r#"
+[[project]]
+name = "foo"
+
+[project.playback]
+fallback = { action = "Teapot" }
+"#;
+        assert!(toml::from_str::<Config>(conf).is_err());
+    }
+
+    #[test]
+    fn test_client_key_without_cert() {
+        let logger = Logger::root(Discard, o!());
+        let t: UpstreamTlsConfig = toml::from_str(r#"client_key = "client.key""#).unwrap();
+        assert!(t.client_config(&logger).is_err());
+        let t: UpstreamTlsConfig = toml::from_str(r#"client_cert = "client.crt""#).unwrap();
+        assert!(t.client_config(&logger).is_err());
+    }
 }
@@ -0,0 +1,203 @@
+use crate::route::PathMatcher;
+use hyper::StatusCode;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Debug, Fail)]
+pub enum FaultRatesError {
+    #[fail(display = "Fault probability {} isn't between 0 and 1", _0)]
+    OutOfRange(f64),
+    #[fail(display = "Fault probabilities add up to {}, more than 1", _0)]
+    TooHigh(f64),
+}
+
+/// A way to break a played back response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Answer with this status instead of the recording
+    Error(StatusCode),
+    /// Reset the connection without answering
+    Reset,
+    /// Wait this long before answering, or never answer
+    Stall(Option<Duration>),
+    /// End the body halfway through
+    Truncate,
+    /// Corrupt the chunked encoding of an HTTP/1.1 body
+    MalformedChunked,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::Error(status) => write!(f, "error {}", status.as_u16()),
+            Fault::Reset => write!(f, "reset"),
+            Fault::Stall(Some(d)) => write!(f, "stall for {}ms", d.as_millis()),
+            Fault::Stall(None) => write!(f, "stall"),
+            Fault::Truncate => write!(f, "truncate"),
+            Fault::MalformedChunked => write!(f, "malformed chunked"),
+        }
+    }
+}
+
+/// How likely each fault is, as probabilities between 0 and 1.  At most one fault is
+/// injected into a response, so they can't add up to more than 1
+#[derive(Debug, Clone)]
+pub struct FaultRates {
+    pub error: f64,
+    pub error_status: StatusCode,
+    pub reset: f64,
+    pub stall: f64,
+    /// `None` stalls forever
+    pub stall_for: Option<Duration>,
+    pub truncate: f64,
+    pub malformed_chunked: f64,
+}
+
+impl Default for FaultRates {
+    fn default() -> FaultRates {
+        FaultRates {
+            error: 0.0,
+            error_status: StatusCode::SERVICE_UNAVAILABLE,
+            reset: 0.0,
+            stall: 0.0,
+            stall_for: None,
+            truncate: 0.0,
+            malformed_chunked: 0.0,
+        }
+    }
+}
+
+impl FaultRates {
+    fn choices(&self) -> [(f64, Fault); 5] {
+        [
+            (self.error, Fault::Error(self.error_status)),
+            (self.reset, Fault::Reset),
+            (self.stall, Fault::Stall(self.stall_for)),
+            (self.truncate, Fault::Truncate),
+            (self.malformed_chunked, Fault::MalformedChunked),
+        ]
+    }
+
+    pub fn check(&self) -> Result<(), FaultRatesError> {
+        // A negative or NaN rate would slip through the total
+        let choices = self.choices();
+        if let Some((rate, _)) = choices.iter().find(|(r, _)| !(0.0..=1.0).contains(r)) {
+            return Err(FaultRatesError::OutOfRange(*rate));
+        }
+        let total: f64 = choices.iter().map(|(rate, _)| rate).sum();
+        if total > 1.0 {
+            Err(FaultRatesError::TooHigh(total))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The fault a roll between 0 and 1 lands on
+    fn pick(&self, roll: f64) -> Option<Fault> {
+        let mut threshold = 0.0;
+        for (rate, fault) in self.choices().iter() {
+            threshold += rate;
+            if roll < threshold {
+                return Some(*fault);
+            }
+        }
+        None
+    }
+}
+
+/// Decides which played back responses to break, from a seeded generator so a run
+/// can be repeated
+pub struct Faults {
+    seed: u64,
+    rng: Mutex<StdRng>,
+    rates: FaultRates,
+    routes: Vec<(PathMatcher, FaultRates)>,
+}
+
+impl Faults {
+    pub fn new(seed: u64, rates: FaultRates) -> Faults {
+        Faults {
+            seed,
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+            rates,
+            routes: Vec::new(),
+        }
+    }
+
+    /// Use `rates` instead of the global ones for paths `matcher` finds
+    pub fn route(mut self, matcher: PathMatcher, rates: FaultRates) -> Faults {
+        self.routes.push((matcher, rates));
+        self
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The fault to inject into the response for `path`, if any.  Every call rolls
+    /// once, whatever the rates, so the sequence only depends on the order of requests
+    pub fn pick(&self, path: &str) -> Option<Fault> {
+        let rates = self
+            .routes
+            .iter()
+            .find(|(m, _)| m.find(path).is_some())
+            .map(|(_, r)| r)
+            .unwrap_or(&self.rates);
+        let roll: f64 = self.rng.lock().unwrap().gen();
+        rates.pick(roll)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Fault, FaultRates, FaultRatesError, Faults};
+    use crate::route::PathMatcher;
+
+    #[test]
+    fn test_seeded_faults() {
+        let rates = FaultRates {
+            error: 0.3,
+            reset: 0.2,
+            ..FaultRates::default()
+        };
+        assert!(rates.check().is_ok());
+        let checkout = FaultRates {
+            truncate: 1.0,
+            ..FaultRates::default()
+        };
+        let faults = |seed| {
+            Faults::new(seed, rates.clone())
+                .route(PathMatcher::prefix("/checkout"), checkout.clone())
+        };
+        let run = |f: &Faults| (0..50).map(|_| f.pick("/items")).collect::<Vec<_>>();
+        let first = run(&faults(7));
+        assert_eq!(first, run(&faults(7)));
+        assert!(first.contains(&Some(Fault::Reset)));
+        assert!(first.contains(&None));
+        assert_eq!(Some(Fault::Truncate), faults(7).pick("/checkout/pay"));
+
+        let too_many = FaultRates {
+            stall: 0.6,
+            ..rates
+        };
+        assert!(too_many.check().is_err());
+    }
+
+    #[test]
+    fn test_rates_out_of_range() {
+        for &rate in &[-0.5, 1.5, f64::NAN, f64::INFINITY] {
+            let rates = FaultRates {
+                error: 0.2,
+                reset: rate,
+                ..FaultRates::default()
+            };
+            match rates.check() {
+                Err(FaultRatesError::OutOfRange(_)) => (),
+                r => panic!("Checked {} as {:?}", rate, r),
+            }
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use failure::Error;
+use hyper::header::{self, HeaderMap, HeaderName};
+use hyper::Method;
+use regex::Regex;
+
+/// Conditions an exchange has to meet for a rule to apply to it.  Conditions left
+/// empty match anything.
+#[derive(Debug, Clone, Default)]
+pub struct FilterRule {
+    /// Any of these request methods
+    pub methods: Vec<Method>,
+    /// Matched against the path the client asked for, without the query
+    pub paths: Vec<Regex>,
+    /// Any of these response media types, e.g. `text/css` or `image/*`
+    pub content_types: Vec<String>,
+    /// All of these request headers are present
+    pub headers: Vec<HeaderName>,
+}
+
+impl FilterRule {
+    /// A regex matching the same paths as `glob`, where `*` stands for any part of
+    /// a path segment, `**` for any number of segments and `?` for one character
+    pub fn glob(glob: &str) -> Result<Regex, Error> {
+        let mut pattern = String::from("^");
+        let mut chars = glob.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    pattern.push_str(".*");
+                }
+                '*' => pattern.push_str("[^/]*"),
+                '?' => pattern.push_str("[^/]"),
+                c => pattern.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        pattern.push('$');
+        Ok(Regex::new(&pattern)?)
+    }
+
+    fn matches(
+        &self,
+        method: &Method,
+        path: &str,
+        request: &HeaderMap,
+        content_type: Option<&str>,
+    ) -> bool {
+        let media_type = content_type
+            .and_then(|c| c.split(';').next())
+            .map(|c| c.trim().to_lowercase());
+        let content_type_matches = |t: &String| {
+            let t = t.to_lowercase();
+            media_type
+                .as_ref()
+                .is_some_and(|m| match t.strip_suffix('*') {
+                    Some(prefix) => m.starts_with(prefix),
+                    None => *m == t,
+                })
+        };
+        (self.methods.is_empty() || self.methods.contains(method))
+            && self.paths.iter().all(|p| p.is_match(path))
+            && (self.content_types.is_empty()
+                || self.content_types.iter().any(content_type_matches))
+            && self.headers.iter().all(|h| request.contains_key(h))
+    }
+}
+
+/// Decides which proxied exchanges get recorded: those matching any `include`
+/// rule (or all of them, if there are none) and no `exclude` rule
+#[derive(Debug, Clone, Default)]
+pub struct RecordFilter {
+    pub include: Vec<FilterRule>,
+    pub exclude: Vec<FilterRule>,
+}
+
+impl RecordFilter {
+    pub fn new(include: Vec<FilterRule>, exclude: Vec<FilterRule>) -> RecordFilter {
+        RecordFilter { include, exclude }
+    }
+
+    pub fn records(
+        &self,
+        method: &Method,
+        path: &str,
+        request: &HeaderMap,
+        response: Option<&HeaderMap>,
+    ) -> bool {
+        let content_type = response
+            .and_then(|r| r.get(header::CONTENT_TYPE))
+            .and_then(|c| c.to_str().ok());
+        let matches = |r: &FilterRule| r.matches(method, path, request, content_type);
+        (self.include.is_empty() || self.include.iter().any(matches))
+            && !self.exclude.iter().any(matches)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FilterRule, RecordFilter};
+    use hyper::header::{self, HeaderMap, HeaderValue};
+    use hyper::Method;
+
+    #[test]
+    fn test_record_filter() {
+        let health = FilterRule {
+            paths: vec![FilterRule::glob("/health*").unwrap()],
+            ..FilterRule::default()
+        };
+        let assets = FilterRule {
+            methods: vec![Method::GET],
+            content_types: vec!["image/*".to_string(), "text/css".to_string()],
+            ..FilterRule::default()
+        };
+        let filter = RecordFilter::new(Vec::new(), vec![health, assets]);
+        let none = HeaderMap::new();
+        let mut css = HeaderMap::new();
+        css.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/css; charset=utf-8"),
+        );
+        assert!(!filter.records(&Method::GET, "/healthz", &none, None));
+        assert!(filter.records(&Method::GET, "/health/deep", &none, None));
+        assert!(!filter.records(&Method::GET, "/site.css", &none, Some(&css)));
+        assert!(filter.records(&Method::POST, "/site.css", &none, Some(&css)));
+
+        let api = FilterRule {
+            paths: vec![FilterRule::glob("/api/**").unwrap()],
+            ..FilterRule::default()
+        };
+        let filter = RecordFilter::new(vec![api], Vec::new());
+        assert!(filter.records(&Method::GET, "/api/v1/users", &none, None));
+        assert!(!filter.records(&Method::GET, "/static/app.js", &none, None));
+    }
+}
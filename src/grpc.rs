@@ -0,0 +1,236 @@
+use crate::archive::{GrpcCall, GrpcMessage};
+use failure::Error;
+use hyper::header::{self, HeaderMap};
+#[cfg(feature = "grpc-json")]
+use prost::Message;
+#[cfg(feature = "grpc-json")]
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor, MethodDescriptor};
+use serde_json::Value;
+#[cfg(feature = "grpc-json")]
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Fail)]
+pub enum GrpcError {
+    #[fail(display = "Truncated gRPC message")]
+    Truncated,
+    #[fail(display = "No descriptor for gRPC method {}", _0)]
+    UnknownMethod(String),
+    #[cfg(not(feature = "grpc-json"))]
+    #[fail(display = "Built without gRPC descriptor support (the grpc-json feature)")]
+    Unsupported,
+}
+
+/// Whether the request or response carries gRPC (but not gRPC-Web) messages
+pub fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v == "application/grpc" || v.starts_with("application/grpc+"))
+        .unwrap_or(false)
+}
+
+/// Split a gRPC body into its length-prefixed messages, returning the compressed
+/// flag and payload of each
+pub fn split_frames(mut body: &[u8]) -> Result<Vec<(bool, &[u8])>, GrpcError> {
+    let mut frames = Vec::new();
+    while !body.is_empty() {
+        if body.len() < 5 {
+            return Err(GrpcError::Truncated);
+        }
+        let len = body[1..5]
+            .iter()
+            .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+        if body.len() < 5 + len {
+            return Err(GrpcError::Truncated);
+        }
+        frames.push((body[0] & 1 != 0, &body[5..5 + len]));
+        body = &body[5 + len..];
+    }
+    Ok(frames)
+}
+
+pub fn frame(compressed: bool, payload: &[u8], out: &mut Vec<u8>) {
+    out.push(if compressed { 1 } else { 0 });
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+/// `(service, method)` from a request path of the form `/package.Service/Method`
+pub fn method_path(path: &str) -> Option<(&str, &str)> {
+    let mut parts = path.trim_start_matches('/').splitn(2, '/');
+    match (parts.next(), parts.next()) {
+        (Some(s), Some(m)) if !s.is_empty() && !m.is_empty() && !m.contains('/') => Some((s, m)),
+        _ => None,
+    }
+}
+
+/// Protobuf descriptors used to show gRPC messages as JSON in recordings
+#[derive(Debug, Clone)]
+pub struct Descriptors {
+    #[cfg(feature = "grpc-json")]
+    pool: DescriptorPool,
+}
+
+#[cfg(feature = "grpc-json")]
+impl Descriptors {
+    /// Load a serialized `FileDescriptorSet`, as written by
+    /// `protoc --include_imports --descriptor_set_out`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Descriptors, Error> {
+        let bytes = fs::read(path)?;
+        Ok(Descriptors {
+            pool: DescriptorPool::decode(bytes.as_slice())?,
+        })
+    }
+
+    fn method(&self, service: &str, method: &str) -> Option<MethodDescriptor> {
+        self.pool
+            .get_service_by_name(service)?
+            .methods()
+            .find(|m| m.name() == method)
+    }
+
+    fn message_type(&self, call: &GrpcCall, request: bool) -> Result<MessageDescriptor, Error> {
+        let method = self
+            .method(&call.service, &call.method)
+            .ok_or_else(|| GrpcError::UnknownMethod(format!("{}/{}", call.service, call.method)))?;
+        Ok(if request {
+            method.input()
+        } else {
+            method.output()
+        })
+    }
+
+    fn describes(&self, call: &GrpcCall, request: bool) -> bool {
+        self.message_type(call, request).is_ok()
+    }
+
+    fn decode(&self, call: &GrpcCall, request: bool, payload: &[u8]) -> Result<Value, Error> {
+        let message = DynamicMessage::decode(self.message_type(call, request)?, payload)?;
+        Ok(serde_json::to_value(&message)?)
+    }
+
+    fn encode(&self, call: &GrpcCall, request: bool, json: &Value) -> Result<Vec<u8>, Error> {
+        let desc = self.message_type(call, request)?;
+        Ok(DynamicMessage::deserialize(desc, json)?.encode_to_vec())
+    }
+}
+
+/// Without protobuf support no descriptor set can be loaded, so messages are always
+/// recorded base64-encoded
+#[cfg(not(feature = "grpc-json"))]
+impl Descriptors {
+    pub fn load<P: AsRef<Path>>(_path: P) -> Result<Descriptors, Error> {
+        Err(GrpcError::Unsupported.into())
+    }
+
+    fn describes(&self, _call: &GrpcCall, _request: bool) -> bool {
+        false
+    }
+
+    fn decode(&self, _call: &GrpcCall, _request: bool, _payload: &[u8]) -> Result<Value, Error> {
+        Err(GrpcError::Unsupported.into())
+    }
+
+    fn encode(&self, _call: &GrpcCall, _request: bool, _json: &Value) -> Result<Vec<u8>, Error> {
+        Err(GrpcError::Unsupported.into())
+    }
+}
+
+impl GrpcCall {
+    pub fn new(service: &str, method: &str) -> GrpcCall {
+        GrpcCall {
+            service: service.to_string(),
+            method: method.to_string(),
+            request: Vec::new(),
+            response: Vec::new(),
+        }
+    }
+
+    /// The request messages as JSON strings, if all of them could be decoded
+    pub fn request_json(&self) -> Option<Vec<String>> {
+        self.request
+            .iter()
+            .map(|m| m.json.as_ref().map(|j| j.to_string()))
+            .collect()
+    }
+
+    /// Split a body into messages, decoding each to JSON when the method is described
+    /// in `descriptors`.  Compressed messages are always kept as bytes.
+    pub fn messages(
+        &self,
+        body: &[u8],
+        request: bool,
+        descriptors: Option<&Descriptors>,
+    ) -> Result<Vec<GrpcMessage>, Error> {
+        let desc = descriptors.filter(|d| d.describes(self, request));
+        split_frames(body)?
+            .into_iter()
+            .map(|(compressed, payload)| {
+                let json = match desc {
+                    Some(d) if !compressed => Some(d.decode(self, request, payload)?),
+                    _ => None,
+                };
+                let data = match json {
+                    Some(_) => None,
+                    None => Some(base64::encode(payload)),
+                };
+                Ok(GrpcMessage {
+                    compressed,
+                    json,
+                    data,
+                })
+            })
+            .collect()
+    }
+
+    /// Frame the recorded messages back into a body, encoding JSON messages with
+    /// `descriptors`
+    pub fn body(
+        &self,
+        messages: &[GrpcMessage],
+        request: bool,
+        descriptors: Option<&Descriptors>,
+    ) -> Result<Vec<u8>, Error> {
+        let mut body = Vec::new();
+        for m in messages {
+            let payload = match (&m.json, &m.data) {
+                (Some(json), _) => descriptors
+                    .ok_or_else(|| {
+                        GrpcError::UnknownMethod(format!("{}/{}", self.service, self.method))
+                    })?
+                    .encode(self, request, json)?,
+                (None, Some(data)) => base64::decode(data)?,
+                (None, None) => Vec::new(),
+            };
+            frame(m.compressed, &payload, &mut body);
+        }
+        Ok(body)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{frame, method_path, split_frames};
+
+    #[test]
+    fn test_frames() {
+        let mut body = Vec::new();
+        frame(false, b"hello", &mut body);
+        frame(true, b"", &mut body);
+        assert_eq!(&[0, 0, 0, 0, 5], &body[..5]);
+        let frames = split_frames(&body).unwrap();
+        assert_eq!(vec![(false, &b"hello"[..]), (true, &b""[..])], frames);
+        assert!(split_frames(&body[..7]).is_err());
+    }
+
+    #[test]
+    fn test_method_path() {
+        assert_eq!(
+            Some(("helloworld.Greeter", "SayHello")),
+            method_path("/helloworld.Greeter/SayHello")
+        );
+        assert_eq!(None, method_path("/"));
+        assert_eq!(None, method_path("/a/b/c"));
+    }
+}
@@ -0,0 +1,228 @@
+use crate::config::DelayOptions;
+use crate::route::PathMatcher;
+use hyper::Method;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::de::{self, Deserialize, Deserializer};
+use serde_derive::Deserialize;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Debug, Fail)]
+#[fail(
+    display = "'{}' isn't a number of bytes per second or \"recorded\"",
+    _0
+)]
+pub struct BandwidthError(String);
+
+/// How fast played back bodies are sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bandwidth {
+    /// At most this many bytes a second
+    Limit(u64),
+    /// As fast as the recorded body arrived: its size over how long it took to arrive
+    Recorded,
+}
+
+impl Bandwidth {
+    /// Bytes per second to send a body of `size` bytes at that took `time` to arrive,
+    /// if it can be worked out
+    pub fn bytes_per_second(self, size: u64, time: Duration) -> Option<u64> {
+        match self {
+            Bandwidth::Limit(0) => None,
+            Bandwidth::Limit(n) => Some(n),
+            Bandwidth::Recorded => {
+                let millis = time.as_millis() as u64;
+                if size == 0 || millis == 0 {
+                    None
+                } else {
+                    Some((size * 1000 / millis).max(1))
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for Bandwidth {
+    type Err = BandwidthError;
+
+    fn from_str(s: &str) -> Result<Bandwidth, BandwidthError> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("recorded") {
+            return Ok(Bandwidth::Recorded);
+        }
+        s.parse()
+            .map(Bandwidth::Limit)
+            .map_err(|_| BandwidthError(s.to_string()))
+    }
+}
+
+impl<'de> Deserialize<'de> for Bandwidth {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Bandwidth, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Limit(u64),
+            Name(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Limit(n) => Ok(Bandwidth::Limit(n)),
+            Raw::Name(n) => n.parse().map_err(de::Error::custom),
+        }
+    }
+}
+
+/// Delay and bandwidth for the requests a route matches
+#[derive(Clone)]
+pub struct DelayRoute {
+    /// Any method when empty
+    methods: Vec<Method>,
+    /// Any path when `None`
+    matcher: Option<PathMatcher>,
+    delay: Option<DelayOptions>,
+    bandwidth: Option<Bandwidth>,
+}
+
+impl DelayRoute {
+    pub fn new(
+        methods: Vec<Method>,
+        matcher: Option<PathMatcher>,
+        delay: Option<DelayOptions>,
+        bandwidth: Option<Bandwidth>,
+    ) -> DelayRoute {
+        DelayRoute {
+            methods,
+            matcher,
+            delay,
+            bandwidth,
+        }
+    }
+
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        (self.methods.is_empty() || self.methods.contains(method))
+            && self.matcher.as_ref().is_none_or(|m| m.find(path).is_some())
+    }
+}
+
+/// Picks the delay for each played back response, drawing from a seeded generator
+/// so a run can be repeated
+pub struct Latency {
+    seed: u64,
+    rng: Mutex<StdRng>,
+    delay: DelayOptions,
+    bandwidth: Option<Bandwidth>,
+    routes: Vec<DelayRoute>,
+}
+
+impl Latency {
+    pub fn new(seed: u64, delay: DelayOptions) -> Latency {
+        Latency {
+            seed,
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+            delay,
+            bandwidth: None,
+            routes: Vec::new(),
+        }
+    }
+
+    pub fn bandwidth(mut self, bandwidth: Option<Bandwidth>) -> Latency {
+        self.bandwidth = bandwidth;
+        self
+    }
+
+    /// Use what the route sets instead of the defaults for the requests it matches;
+    /// routes are tried in order
+    pub fn route(mut self, route: DelayRoute) -> Latency {
+        self.routes.push(route);
+        self
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Whether any of the delays are drawn at random
+    pub fn is_random(&self) -> bool {
+        self.delay.is_random()
+            || self
+                .routes
+                .iter()
+                .any(|r| r.delay.is_some_and(|d| d.is_random()))
+    }
+
+    /// The delay options for a request
+    pub fn options(&self, method: &Method, path: &str) -> DelayOptions {
+        self.routes
+            .iter()
+            .filter(|r| r.matches(method, path))
+            .find_map(|r| r.delay)
+            .unwrap_or(self.delay)
+    }
+
+    /// How fast to send the body of the response to a request, if it's limited
+    pub fn bandwidth_for(&self, method: &Method, path: &str) -> Option<Bandwidth> {
+        self.routes
+            .iter()
+            .filter(|r| r.matches(method, path))
+            .find_map(|r| r.bandwidth)
+            .or(self.bandwidth)
+    }
+
+    /// A delay drawn from `options`, for the options that don't depend on the recording
+    pub fn sample(&self, options: &DelayOptions) -> Duration {
+        options.sample(&mut *self.rng.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Bandwidth, DelayRoute, Latency};
+    use crate::config::DelayOptions;
+    use crate::route::PathMatcher;
+    use hyper::Method;
+    use std::time::Duration;
+
+    #[test]
+    fn test_seeded_latency() {
+        let tail = DelayOptions::LogNormal {
+            p50_millis: 20.0,
+            p99_millis: 400.0,
+        };
+        let uploads = DelayRoute::new(
+            vec![Method::POST],
+            Some(PathMatcher::prefix("/uploads")),
+            Some(DelayOptions::Static { millis: 250 }),
+            Some(Bandwidth::Recorded),
+        );
+        let latency = |seed| Latency::new(seed, tail).route(uploads.clone());
+
+        let a = latency(11);
+        let b = latency(11);
+        let draws: Vec<Duration> = (0..200).map(|_| a.sample(&tail)).collect();
+        assert_eq!(draws, (0..200).map(|_| b.sample(&tail)).collect::<Vec<_>>());
+        let mut sorted = draws.clone();
+        sorted.sort();
+        let median = sorted[100].as_millis();
+        assert!(median > 10 && median < 40, "median {}", median);
+        assert!(sorted[199] > sorted[100] * 5);
+
+        assert_eq!(
+            DelayOptions::Static { millis: 250 },
+            a.options(&Method::POST, "/uploads/1")
+        );
+        assert_eq!(tail, a.options(&Method::GET, "/uploads/1"));
+        assert!(a.is_random());
+        assert_eq!(
+            Some(Bandwidth::Recorded),
+            a.bandwidth_for(&Method::POST, "/uploads/1")
+        );
+        assert_eq!(None, a.bandwidth_for(&Method::GET, "/items"));
+        assert_eq!(
+            Some(2048),
+            Bandwidth::Recorded.bytes_per_second(4096, Duration::from_secs(2))
+        );
+        assert_eq!(Bandwidth::Limit(1024), "1024".parse().unwrap());
+        assert!("fast".parse::<Bandwidth>().is_err());
+    }
+}
@@ -0,0 +1,174 @@
+use crate::trailers::TrailerIo;
+use futures::future::{self, FutureResult, Join};
+use futures::stream::FuturesUnordered;
+use futures::{Async, Future, Poll, Stream};
+use hyper::server::conn::AddrIncoming;
+use rustls::{ServerConfig, ServerSession};
+use slog::Logger;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::{Accept, TlsAcceptor, TlsStream};
+
+/// Makes a connection end with a TCP reset (RST) instead of the usual close once it's
+/// set, by turning lingering off just before the socket is closed
+#[derive(Clone, Default)]
+pub struct ResetOnClose(Arc<AtomicBool>);
+
+impl ResetOnClose {
+    pub fn set(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    fn is_set(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+enum Transport {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream, ServerSession>>),
+}
+
+/// A connection accepted by `Listener`
+pub struct ServerStream {
+    transport: Transport,
+    remote_addr: SocketAddr,
+    reset: ResetOnClose,
+}
+
+impl ServerStream {
+    fn new(transport: Transport, remote_addr: SocketAddr) -> ServerStream {
+        ServerStream {
+            transport,
+            remote_addr,
+            reset: ResetOnClose::default(),
+        }
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    pub fn reset_on_close(&self) -> ResetOnClose {
+        self.reset.clone()
+    }
+
+    fn tcp(&self) -> &TcpStream {
+        match self.transport {
+            Transport::Plain(ref s) => s,
+            Transport::Tls(ref s) => s.get_ref().0,
+        }
+    }
+}
+
+impl Drop for ServerStream {
+    fn drop(&mut self) {
+        if self.reset.is_set() {
+            let _ = self.tcp().set_linger(Some(Duration::from_secs(0)));
+        }
+    }
+}
+
+impl Read for ServerStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.transport {
+            Transport::Plain(ref mut s) => s.read(buf),
+            Transport::Tls(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for ServerStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.transport {
+            Transport::Plain(ref mut s) => s.write(buf),
+            Transport::Tls(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.transport {
+            Transport::Plain(ref mut s) => s.flush(),
+            Transport::Tls(ref mut s) => s.flush(),
+        }
+    }
+}
+
+impl AsyncRead for ServerStream {}
+
+impl AsyncWrite for ServerStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self.transport {
+            Transport::Plain(ref mut s) => s.shutdown(),
+            Transport::Tls(ref mut s) => s.shutdown(),
+        }
+    }
+}
+
+/// Accepts connections like `Server::bind`, terminating TLS when it's configured
+/// and wrapping each connection in `TrailerIo`.
+///
+/// Handshakes run alongside each other, so a client that never finishes one
+/// doesn't hold up the rest.
+pub struct Listener {
+    logger: Logger,
+    inner: AddrIncoming,
+    tls: Option<TlsAcceptor>,
+    handshakes: FuturesUnordered<Join<Accept<TcpStream>, FutureResult<SocketAddr, io::Error>>>,
+}
+
+impl Listener {
+    pub fn bind(
+        logger: Logger,
+        addr: &SocketAddr,
+        tls: Option<Arc<ServerConfig>>,
+    ) -> Result<Listener, hyper::Error> {
+        Ok(Listener {
+            logger,
+            inner: AddrIncoming::bind(addr)?,
+            tls: tls.map(TlsAcceptor::from),
+            handshakes: FuturesUnordered::new(),
+        })
+    }
+}
+
+impl Stream for Listener {
+    type Item = TrailerIo<ServerStream>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        let acceptor = match self.tls {
+            Some(ref a) => a,
+            None => {
+                let accepted = self.inner.poll()?;
+                return Ok(accepted.map(|s| {
+                    s.map(|s| {
+                        let addr = s.remote_addr();
+                        let stream = ServerStream::new(Transport::Plain(s.into_inner()), addr);
+                        TrailerIo::server(stream)
+                    })
+                }));
+            }
+        };
+        while let Async::Ready(Some(s)) = self.inner.poll()? {
+            let addr = s.remote_addr();
+            let handshake = acceptor.accept(s.into_inner()).join(future::ok(addr));
+            self.handshakes.push(handshake);
+        }
+        loop {
+            match self.handshakes.poll() {
+                Ok(Async::Ready(Some((s, addr)))) => {
+                    let stream = ServerStream::new(Transport::Tls(Box::new(s)), addr);
+                    return Ok(Async::Ready(Some(TrailerIo::server(stream))));
+                }
+                Ok(_) => return Ok(Async::NotReady),
+                Err(e) => debug!(self.logger, "TLS handshake failed: {}", e),
+            }
+        }
+    }
+}
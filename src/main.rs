@@ -7,8 +7,22 @@ extern crate failure;
 mod archive;
 mod cli;
 mod config;
+mod faults;
+mod filter;
+mod grpc;
+mod latency;
+mod listener;
 mod playback;
 mod proxy;
+mod rewrite;
+mod route;
+mod status;
+mod tee;
+mod template;
+mod tls;
+mod trailers;
+mod upstream;
+mod websocket;
 
 use failure::Error;
 use slog_async;
@@ -43,6 +57,10 @@ fn main() -> Result<(), Error> {
             let server = playback::get_playback_servers(root_logger, servers);
             rt::run(server);
         }
+        CliConfig::Pattern { file, pattern } => {
+            let changed = archive::write_pattern(&file, pattern.as_ref())?;
+            println!("Updated {} entries in {}", changed, file.display());
+        }
     }
 
     Ok(())
@@ -1,27 +1,139 @@
-use crate::archive::{ArchivedRequest, RequestFacts};
-use crate::config::{DelayOptions, PlaybackServerConfig};
+use crate::archive::{
+    precondition, precondition_response, ArchivedRequest, ContentEncoding, Direction, GrpcCall,
+    ReplayBody, RequestFacts, WebSocketMessage,
+};
+use crate::config::{DelayOptions, PlaybackServerConfig, WebSocketOptions};
+use crate::faults::{Fault, Faults};
+use crate::grpc::{self, Descriptors};
+use crate::latency::Latency;
+use crate::listener::{Listener, ResetOnClose, ServerStream};
+use crate::proxy::Passthrough;
+use crate::rewrite::OriginRewrite;
+use crate::trailers::{TrailerIo, TrailerSlot};
+use crate::websocket::{self, Frame, FrameCodec, DEFAULT_MAX_FRAME_SIZE};
+use bytes::Bytes;
 use failure::Error;
-use futures::future::{self, Either, FutureResult};
-use futures::{Future, Stream};
+use futures::future::{self, Either, FutureResult, Loop};
+use futures::stream::{SplitSink, SplitStream};
+use futures::{Future, Sink, Stream};
 use hyper::http::request::Parts as RequestParts;
 use hyper::service::{MakeService, Service};
-use hyper::{header, Body, Chunk, Request, Response, Server};
+use hyper::upgrade::Upgraded;
+use hyper::{
+    header, rt, Body, Chunk, HeaderMap, Method, Request, Response, Server, StatusCode, Version,
+};
 use slog::Logger;
+use std::collections::VecDeque;
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::codec::{Decoder, Framed};
+use tokio::timer::Delay;
+
+#[derive(Debug, Fail)]
+#[fail(display = "No recording matched, closing the connection")]
+pub struct FallbackClosed;
+
+#[derive(Debug, Fail)]
+#[fail(display = "Dropping the connection for an injected fault")]
+pub struct FaultReset;
+
+/// What playback answers with when no recording matches a request
+#[derive(Default)]
+pub enum Fallback {
+    /// A plain `404 Not Found`
+    #[default]
+    NotFound,
+    /// A fixed response
+    Respond {
+        status: StatusCode,
+        headers: HeaderMap,
+        body: Bytes,
+    },
+    /// A recorded response, served like a matched one
+    Recording(Box<ArchivedRequest>),
+    /// Drop the connection without answering
+    Close,
+    /// Forward the request upstream
+    Forward(Box<Passthrough>),
+}
+
+impl Fallback {
+    fn recording(&self) -> Option<&ArchivedRequest> {
+        match self {
+            Fallback::Recording(r) => Some(r),
+            _ => None,
+        }
+    }
+
+    fn respond(
+        &self,
+        parts: RequestParts,
+        body: Chunk,
+        logger: Logger,
+    ) -> Box<dyn Future<Item = Response<ReplayBody>, Error = Error> + Send> {
+        match self {
+            Fallback::Respond {
+                status,
+                headers,
+                body,
+            } => {
+                let mut response = Response::new(Body::from(body.clone()).into());
+                *response.status_mut() = *status;
+                *response.headers_mut() = headers.clone();
+                Box::new(future::ok(response))
+            }
+            Fallback::Close => {
+                info!(logger, "Closing the connection");
+                Box::new(future::err(FallbackClosed.into()))
+            }
+            Fallback::Forward(p) => match p.route(parts.uri.path()) {
+                Some(route) => {
+                    info!(logger, "Forwarding request upstream");
+                    let req = Request::from_parts(parts, Body::from(body));
+                    Box::new(
+                        p.forward(route, req, logger)
+                            .map(|r| r.map(ReplayBody::from)),
+                    )
+                }
+                None => Box::new(future::ok(not_found())),
+            },
+            Fallback::NotFound | Fallback::Recording(_) => Box::new(future::ok(not_found())),
+        }
+    }
+}
 
 pub struct MakePlaybackService {
     logger: Logger,
     transactions: Arc<RwLock<Vec<ArchivedRequest>>>,
-    delay: DelayOptions,
+    latency: Arc<Latency>,
+    websocket: WebSocketOptions,
+    descriptors: Option<Descriptors>,
+    rewrite: Option<OriginRewrite>,
+    passthrough: Option<Passthrough>,
+    fallback: Arc<Fallback>,
+    faults: Option<Arc<Faults>>,
+    /// Shift recorded dates to make responses look fresh
+    refresh_dates: bool,
 }
 
 pub struct PlaybackService {
     logger: Logger,
+    trailer_slot: TrailerSlot,
     transactions: Arc<RwLock<Vec<ArchivedRequest>>>,
-    delay: DelayOptions,
+    latency: Arc<Latency>,
+    websocket: WebSocketOptions,
+    descriptors: Option<Descriptors>,
+    rewrite: Option<OriginRewrite>,
+    passthrough: Option<Passthrough>,
+    fallback: Arc<Fallback>,
+    faults: Option<Arc<Faults>>,
+    /// Turns the close after an injected reset into a real one
+    reset: ResetOnClose,
+    /// Shift recorded dates to make responses look fresh
+    refresh_dates: bool,
 }
 
-impl<C> MakeService<C> for MakePlaybackService {
+impl<'a> MakeService<&'a TrailerIo<ServerStream>> for MakePlaybackService {
     type ReqBody = <PlaybackService as Service>::ReqBody;
     type ResBody = <PlaybackService as Service>::ResBody;
     type Error = <PlaybackService as Service>::Error;
@@ -29,19 +141,29 @@ impl<C> MakeService<C> for MakePlaybackService {
     type Service = PlaybackService;
     type MakeError = Error;
 
-    fn make_service(&mut self, _ctx: C) -> Self::Future {
+    fn make_service(&mut self, conn: &'a TrailerIo<ServerStream>) -> Self::Future {
         trace!(self.logger, "Creating Playback Service");
-        future::ok(PlaybackService::new(
-            self.logger.clone(),
-            self.transactions.clone(),
-            self.delay,
-        ))
+        future::ok(
+            PlaybackService::new(
+                self.logger.clone(),
+                conn.slot(),
+                self.transactions.clone(),
+                self.latency.clone(),
+                self.websocket,
+                self.descriptors.clone(),
+                self.rewrite.clone(),
+            )
+            .passthrough(self.passthrough.clone())
+            .fallback(self.fallback.clone())
+            .faults(self.faults.clone(), conn.get_ref().reset_on_close())
+            .refresh_dates(self.refresh_dates),
+        )
     }
 }
 
 impl Service for PlaybackService {
     type ReqBody = Body;
-    type ResBody = Body;
+    type ResBody = ReplayBody;
     type Error = Error;
     type Future = Box<Future<Item = Response<Self::ResBody>, Error = Self::Error> + Send>;
     fn call(&mut self, req: Request<Self::ReqBody>) -> Self::Future {
@@ -55,26 +177,108 @@ impl Service for PlaybackService {
             .unwrap_or_else(|| "/".to_string());
 
         let logger = self.logger.new(o!("method" => method, "path" => path));
-        let delay = self.delay;
+        if let Some(ref p) = self.passthrough {
+            if let Some(route) = p.route(parts.uri.path()) {
+                info!(logger, "Passing request through"; "route" => route.name.clone());
+                let req = Request::from_parts(parts, body);
+                return Box::new(
+                    p.forward(route, req, logger)
+                        .map(|r| r.map(ReplayBody::from)),
+                );
+            }
+        }
+        if websocket::is_upgrade_request(&parts.headers) {
+            return self.websocket(parts, body, logger);
+        }
+        let delay = self.latency.options(&parts.method, parts.uri.path());
+        let bandwidth = self.latency.bandwidth_for(&parts.method, parts.uri.path());
+        let latency = self.latency.clone();
+        let descriptors = self.descriptors.clone();
+        let rewrite = self.rewrite.clone();
+        // hyper won't write trailers on HTTP/1, but the connection can
+        let trailer_slot = if parts.version == Version::HTTP_11 && parts.method != Method::HEAD {
+            Some(self.trailer_slot.clone())
+        } else {
+            None
+        };
+        let fallback = self.fallback.clone();
+        let faults = self.faults.clone();
+        let reset = self.reset.clone();
+        let refresh_dates = self.refresh_dates;
         let r = body.concat2().map_err(Error::from).and_then(move |b| {
             let transactions = &transactions.read().unwrap();
-            if let Some(m) = find_match(&transactions, &parts, b.into_bytes().to_vec()) {
-                info!(logger, "Serving archived response");
-                let response = m.hyper_response();
-                Either::A(
-                    m.delay(&delay)
-                        .map_err(Error::from)
-                        .and_then(move |_| response),
-                )
-            } else {
-                error!(logger, "Response for request not found in archives");
-                Either::B(future::ok(
-                    Response::builder()
-                        .status(404)
-                        .body(Body::from(Chunk::from("Not Found")))
-                        .unwrap(),
-                ))
+            let descriptors = descriptors.as_ref();
+            let m = match find_match(transactions, &parts, b.to_vec(), descriptors) {
+                Some(m) => {
+                    info!(logger, "Serving archived response");
+                    m
+                }
+                None => {
+                    error!(logger, "Response for request not found in archives");
+                    match fallback.recording() {
+                        Some(m) => {
+                            info!(logger, "Serving fallback recording");
+                            m
+                        }
+                        None => return Either::B(fallback.respond(parts, b, logger)),
+                    }
+                }
+            };
+            let fault = faults.as_ref().and_then(|f| f.pick(parts.uri.path()));
+            if let Some(f) = fault {
+                warn!(logger, "Injecting fault"; "fault" => f.to_string());
             }
+            let stall: Box<dyn Future<Item = (), Error = Error> + Send> = match fault {
+                Some(Fault::Error(status)) => {
+                    return Either::B(Box::new(future::ok(fault_response(status))));
+                }
+                Some(Fault::Reset) => {
+                    // HTTP/2 resets just the stream, not the connection the others share
+                    if parts.version < Version::HTTP_2 {
+                        reset.set();
+                    }
+                    return Either::B(Box::new(future::err(FaultReset.into())));
+                }
+                Some(Fault::Stall(Some(d))) => {
+                    Box::new(Delay::new(Instant::now() + d).map_err(Error::from))
+                }
+                Some(Fault::Stall(None)) => Box::new(future::empty()),
+                _ => Box::new(future::ok(())),
+            };
+            let response = m
+                .hyper_response(&parts, &b, &delay, descriptors, rewrite.as_ref())
+                .map(|mut r| {
+                    if refresh_dates {
+                        m.refresh_dates(r.headers_mut());
+                    }
+                    // Compared after refreshing, since the client cached the refreshed dates
+                    if let Some(status) = precondition(&parts, r.status(), r.headers()) {
+                        info!(logger, "Answering conditional request"; "status" => status.as_u16());
+                        return precondition_response(r, status);
+                    }
+                    if let Some(slot) = trailer_slot {
+                        // Only an HTTP/1.1 body can be sent chunked
+                        if fault == Some(Fault::MalformedChunked) {
+                            slot.malform();
+                            r.headers_mut().remove(header::CONTENT_LENGTH);
+                            r.body_mut().chunked();
+                        }
+                        r.body_mut().trailer_slot(slot);
+                    }
+                    if fault == Some(Fault::Truncate) {
+                        r.body_mut().truncate();
+                    }
+                    if let Some(rate) = bandwidth.and_then(|b| m.bytes_per_second(b)) {
+                        r.body_mut().throttle(rate);
+                    }
+                    r
+                });
+            Either::A(
+                m.delay(&delay, &latency)
+                    .map_err(Error::from)
+                    .and_then(move |_| stall)
+                    .and_then(move |_| response),
+            )
         });
         Box::new(r)
     }
@@ -84,31 +288,276 @@ impl MakePlaybackService {
     pub fn new(
         logger: Logger,
         transactions: Vec<ArchivedRequest>,
-        delay: DelayOptions,
+        latency: Latency,
+        websocket: WebSocketOptions,
+        descriptors: Option<Descriptors>,
+        rewrite: Option<OriginRewrite>,
     ) -> MakePlaybackService {
         MakePlaybackService {
             logger,
             transactions: Arc::new(RwLock::new(transactions)),
-            delay,
+            latency: Arc::new(latency),
+            websocket,
+            descriptors,
+            rewrite,
+            passthrough: None,
+            fallback: Arc::new(Fallback::default()),
+            faults: None,
+            refresh_dates: false,
         }
     }
+
+    pub fn passthrough(mut self, passthrough: Option<Passthrough>) -> MakePlaybackService {
+        self.passthrough = passthrough;
+        self
+    }
+
+    pub fn fallback(mut self, fallback: Fallback) -> MakePlaybackService {
+        self.fallback = Arc::new(fallback);
+        self
+    }
+
+    pub fn faults(mut self, faults: Option<Faults>) -> MakePlaybackService {
+        self.faults = faults.map(Arc::new);
+        self
+    }
+
+    pub fn refresh_dates(mut self, refresh_dates: bool) -> MakePlaybackService {
+        self.refresh_dates = refresh_dates;
+        self
+    }
 }
 
 impl PlaybackService {
     fn new(
         logger: Logger,
+        trailer_slot: TrailerSlot,
         transactions: Arc<RwLock<Vec<ArchivedRequest>>>,
-        delay: DelayOptions,
+        latency: Arc<Latency>,
+        websocket: WebSocketOptions,
+        descriptors: Option<Descriptors>,
+        rewrite: Option<OriginRewrite>,
     ) -> PlaybackService {
         PlaybackService {
             logger,
+            trailer_slot,
             transactions,
-            delay,
+            latency,
+            websocket,
+            descriptors,
+            rewrite,
+            passthrough: None,
+            fallback: Arc::new(Fallback::default()),
+            faults: None,
+            reset: ResetOnClose::default(),
+            refresh_dates: false,
+        }
+    }
+
+    fn passthrough(mut self, passthrough: Option<Passthrough>) -> PlaybackService {
+        self.passthrough = passthrough;
+        self
+    }
+
+    fn fallback(mut self, fallback: Arc<Fallback>) -> PlaybackService {
+        self.fallback = fallback;
+        self
+    }
+
+    fn faults(mut self, faults: Option<Arc<Faults>>, reset: ResetOnClose) -> PlaybackService {
+        self.faults = faults;
+        self.reset = reset;
+        self
+    }
+
+    fn refresh_dates(mut self, refresh_dates: bool) -> PlaybackService {
+        self.refresh_dates = refresh_dates;
+        self
+    }
+
+    /// Answer a recorded WebSocket handshake and replay the recorded frames once the
+    /// connection has switched protocols
+    fn websocket(
+        &self,
+        parts: RequestParts,
+        body: Body,
+        logger: Logger,
+    ) -> <Self as Service>::Future {
+        let transactions = self.transactions.read().unwrap();
+        let m = match find_match(&transactions, &parts, Vec::new(), None) {
+            Some(m) if m.is_websocket() => m,
+            _ => {
+                error!(logger, "WebSocket handshake not found in archives");
+                return Box::new(future::ok(not_found()));
+            }
+        };
+        let delay = self.latency.options(&parts.method, parts.uri.path());
+        let mut response = match m.hyper_response(&parts, &[], &delay, None, None) {
+            Ok(r) => r,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        {
+            let headers = response.headers_mut();
+            headers.remove(header::SEC_WEBSOCKET_EXTENSIONS);
+            if let Some(key) = parts.headers.get(header::SEC_WEBSOCKET_KEY) {
+                headers.insert(
+                    header::SEC_WEBSOCKET_ACCEPT,
+                    websocket::accept_key(key.as_bytes()),
+                );
+            }
+        }
+        let state = ReplayState {
+            script: m.websocket_messages().iter().cloned().collect(),
+            scale: match delay {
+                DelayOptions::Original { scale } => Some(scale),
+                _ => None,
+            },
+            wait_for_client: self.websocket.wait_for_client,
+            max_frame_size: self
+                .websocket
+                .max_frame_size
+                .unwrap_or(DEFAULT_MAX_FRAME_SIZE),
+            anchor: Instant::now(),
+            anchor_time: 0,
+            closed: false,
+            logger: logger.new(o!("area" => "websocket")),
+        };
+        let err_logger = logger.new(o!("area" => "websocket-error"));
+        let replay = body
+            .on_upgrade()
+            .map_err(Error::from)
+            .and_then(move |io| replay_frames(io, state))
+            .map_err(move |e| error!(err_logger, "{}", e));
+        rt::spawn(replay);
+        info!(logger, "Serving archived WebSocket handshake");
+        Box::new(
+            m.delay(&delay, &self.latency)
+                .map_err(Error::from)
+                .map(move |_| response),
+        )
+    }
+}
+
+fn fault_response(status: StatusCode) -> Response<ReplayBody> {
+    let reason = status.canonical_reason().unwrap_or("Injected fault");
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(Chunk::from(reason)).into())
+        .unwrap()
+}
+
+fn not_found() -> Response<ReplayBody> {
+    Response::builder()
+        .status(404)
+        .body(Body::from(Chunk::from("Not Found")).into())
+        .unwrap()
+}
+
+type FrameSink = SplitSink<Framed<Upgraded, FrameCodec>>;
+type FrameStream = SplitStream<Framed<Upgraded, FrameCodec>>;
+type ReplayStep = Box<dyn Future<Item = Loop<ReplayLoop, ReplayLoop>, Error = Error> + Send>;
+type ReplayLoop = (FrameSink, FrameStream, ReplayState);
+
+struct ReplayState {
+    script: VecDeque<WebSocketMessage>,
+    /// Replay frames at their recorded times, multiplied by this
+    scale: Option<f64>,
+    wait_for_client: bool,
+    max_frame_size: usize,
+    /// `anchor_time` in the recording corresponds to `anchor` in this replay
+    anchor: Instant,
+    anchor_time: u64,
+    closed: bool,
+    logger: Logger,
+}
+
+fn replay_frames(io: Upgraded, state: ReplayState) -> impl Future<Item = (), Error = Error> {
+    let (sink, stream) = FrameCodec::new(state.max_frame_size).framed(io).split();
+    future::loop_fn((sink, stream, state), replay_step).and_then(|(sink, stream, state)| {
+        if state.closed {
+            return Either::A(future::ok(()));
+        }
+        // The recording ended before the closing handshake, so answer the client's close
+        let close = stream
+            .filter(Frame::is_close)
+            .into_future()
+            .map_err(|(e, _)| Error::from(e))
+            .and_then(move |(close, _)| match close {
+                Some(f) => Either::A(
+                    sink.send(Frame {
+                        fin: true,
+                        opcode: f.opcode,
+                        mask: None,
+                        payload: f.payload,
+                    })
+                    .map(|_| ())
+                    .map_err(Error::from),
+                ),
+                None => Either::B(future::ok(())),
+            });
+        Either::B(close)
+    })
+}
+
+fn replay_step((sink, stream, mut state): ReplayLoop) -> ReplayStep {
+    let message = match state.script.pop_front() {
+        Some(m) => m,
+        None => return Box::new(future::ok(Loop::Break((sink, stream, state)))),
+    };
+    match message.direction {
+        Direction::Send => {
+            if !state.wait_for_client {
+                return Box::new(future::ok(Loop::Continue((sink, stream, state))));
+            }
+            let expected = websocket::message_payload(&message);
+            let fut = stream.into_future().map_err(|(e, _)| Error::from(e)).map(
+                move |(frame, stream)| match frame {
+                    Some(f) => {
+                        if expected.ok().as_ref() != Some(&f.payload) {
+                            warn!(state.logger, "Client frame differs from recording");
+                        }
+                        state.closed = f.is_close();
+                        state.anchor = Instant::now();
+                        state.anchor_time = message.time;
+                        Loop::Continue((sink, stream, state))
+                    }
+                    None => {
+                        info!(state.logger, "Client closed the connection");
+                        state.closed = true;
+                        Loop::Break((sink, stream, state))
+                    }
+                },
+            );
+            Box::new(fut)
+        }
+        Direction::Receive => {
+            let frame = match Frame::from_message(&message) {
+                Ok(f) => f,
+                Err(e) => return Box::new(future::err(e)),
+            };
+            let at = match state.scale {
+                Some(scale) => {
+                    let offset = message.time.saturating_sub(state.anchor_time);
+                    state.anchor + Duration::from_millis(offset).mul_f64(scale)
+                }
+                None => Instant::now(),
+            };
+            state.closed = state.closed || frame.is_close();
+            let fut = Delay::new(at)
+                .map_err(Error::from)
+                .and_then(move |_| sink.send(frame).map_err(Error::from))
+                .map(move |sink| Loop::Continue((sink, stream, state)));
+            Box::new(fut)
         }
     }
 }
 
-fn hyper_request_to_facts(parts: &RequestParts, body: Vec<u8>) -> Vec<RequestFacts> {
+fn hyper_request_to_facts(
+    parts: &RequestParts,
+    body: Vec<u8>,
+    descriptors: Option<&Descriptors>,
+) -> Vec<RequestFacts> {
     let mut results = Vec::with_capacity(4);
     let method = parts.method.clone();
     results.push(RequestFacts::Method(method));
@@ -127,6 +576,13 @@ fn hyper_request_to_facts(parts: &RequestParts, body: Vec<u8>) -> Vec<RequestFac
         .unwrap_or_else(|| "")
         .to_string();
 
+    let body = match ContentEncoding::from_headers(&parts.headers) {
+        Some(enc) => enc.decode(&body).unwrap_or(body),
+        None => body,
+    };
+    if let Some(messages) = grpc_request_json(parts, &body, descriptors) {
+        results.push(RequestFacts::GrpcRequest(messages));
+    }
     if !body.is_empty() {
         results.push(RequestFacts::Body {
             content_type,
@@ -144,13 +600,33 @@ fn hyper_request_to_facts(parts: &RequestParts, body: Vec<u8>) -> Vec<RequestFac
     results
 }
 
+/// The request messages of a gRPC call as JSON, for matching against decoded recordings
+fn grpc_request_json(
+    parts: &RequestParts,
+    body: &[u8],
+    descriptors: Option<&Descriptors>,
+) -> Option<Vec<String>> {
+    if !grpc::is_grpc(&parts.headers) {
+        return None;
+    }
+    let (service, method) = grpc::method_path(parts.uri.path())?;
+    let mut call = GrpcCall::new(service, method);
+    call.request = call.messages(body, true, descriptors).ok()?;
+    call.request_json()
+}
+
 fn find_match<'a, 'b>(
     transactions: &'a [ArchivedRequest],
     parts: &'b RequestParts,
     body: Vec<u8>,
+    descriptors: Option<&Descriptors>,
 ) -> Option<&'a ArchivedRequest> {
-    let facts = hyper_request_to_facts(&parts, body);
-    transactions.iter().find(|t| t.matches(&facts))
+    let facts = hyper_request_to_facts(parts, body, descriptors);
+    // A recording of the exact path wins over a pattern that covers it
+    transactions
+        .iter()
+        .find(|t| !t.is_pattern() && t.matches(&facts))
+        .or_else(|| transactions.iter().find(|t| t.matches(&facts)))
 }
 
 pub fn get_playback_servers<I: IntoIterator<Item = PlaybackServerConfig>>(
@@ -161,18 +637,427 @@ pub fn get_playback_servers<I: IntoIterator<Item = PlaybackServerConfig>>(
         let req_logger = logger.new(o!("server" => s.name.to_string(), "lifecycle" => "run"));
         let start_logger = req_logger.new(o!("lifecycle" => "startup"));
         let serve_logger = req_logger.new(o!("lifecycle" => "error"));
+        let listen_logger = req_logger.new(o!("lifecycle" => "accept"));
         let socket = s.socket;
-        let factory = MakePlaybackService::new(req_logger, s.archives, s.delay);
+        let protocol = s.protocol;
+        let tls = s.tls.clone();
+        let seed = s.faults.as_ref().map(Faults::seed);
+        let delay_seed = Some(s.latency.seed()).filter(|_| s.latency.is_random());
+        let factory = MakePlaybackService::new(
+            req_logger,
+            s.archives,
+            s.latency,
+            s.websocket,
+            s.descriptors,
+            s.rewrite,
+        )
+        .passthrough(s.passthrough)
+        .fallback(s.fallback)
+        .faults(s.faults)
+        .refresh_dates(s.refresh_dates);
         future::lazy(move || {
             info!(start_logger, "Playback listening on {}", &socket);
+            if let Some(seed) = delay_seed {
+                info!(start_logger, "Drawing random delays"; "seed" => seed);
+            }
+            if let Some(seed) = seed {
+                info!(start_logger, "Injecting faults"; "seed" => seed);
+            }
             Ok(())
         })
         .then(move |_: Result<(), ()>| {
-            Server::bind(&socket)
-                .serve(factory)
-                .map_err(move |e| error!(serve_logger, "{}", e))
+            match Listener::bind(listen_logger, &socket, tls) {
+                Ok(incoming) => Either::A(
+                    protocol
+                        .configure(Server::builder(incoming))
+                        .serve(factory)
+                        .map_err(move |e| error!(serve_logger, "{}", e)),
+                ),
+                Err(e) => {
+                    error!(serve_logger, "Couldn't listen on {}: {}", &socket, e);
+                    Either::B(future::err(()))
+                }
+            }
         })
     });
 
     future::join_all(futs).map(|_| ()).map_err(|_| ())
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Fallback, PlaybackService};
+    use crate::archive::{ArchivedRequest, HarLoader, ReplayBody};
+    use crate::config::{DelayOptions, ForwardedHeaders, ProxyServerConfig, WebSocketOptions};
+    use crate::grpc;
+    use crate::latency::{Bandwidth, Latency};
+    use crate::listener::Listener;
+    use crate::proxy::MakeProxyService;
+    use crate::trailers::TrailerSlot;
+    use bytes::Bytes;
+    use futures::{stream, Future, Stream};
+    use hyper::body::Payload;
+    use hyper::header::{HeaderMap, HeaderValue};
+    use hyper::service::{service_fn_ok, Service};
+    use hyper::{Body, Client, Request, Response, Server, StatusCode};
+    use serde_json::{json, Value};
+    use slog::{Discard, Logger};
+    use std::fs;
+    use std::net::{SocketAddr, TcpListener};
+    use std::path::Path;
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tokio::runtime::current_thread::{self, Runtime};
+
+    /// A recording of `request` answered with `response`
+    fn entry(request: Value, response: Value) -> Value {
+        json!({
+            "startedDateTime": "2019-02-01T12:00:00+00:00",
+            "time": 20,
+            "request": request,
+            "response": response,
+            "cache": {},
+            "timings": { "send": 0, "wait": 10, "receive": 10 }
+        })
+    }
+
+    /// A recording of `GET /recorded` answered with `body`
+    fn recording(headers: &[(&str, &str)], body: &str) -> ArchivedRequest {
+        load(recording_entry(headers, body))
+    }
+
+    /// The entry of a [`recording`], to adjust before it's loaded
+    fn recording_entry(headers: &[(&str, &str)], body: &str) -> Value {
+        let headers: Vec<_> = headers
+            .iter()
+            .map(|(name, value)| json!({ "name": name, "value": value }))
+            .collect();
+        let request = json!({
+            "method": "GET", "url": "http://example.com/recorded",
+            "httpVersion": "HTTP/1.1", "cookies": [], "headers": [],
+            "queryString": [], "headersSize": -1, "bodySize": -1
+        });
+        let response = json!({
+            "status": 200, "statusText": "OK", "httpVersion": "HTTP/1.1",
+            "cookies": [], "headers": headers,
+            "content": { "size": body.len(), "mimeType": "text/plain", "text": body },
+            "redirectURL": "", "headersSize": -1, "bodySize": -1
+        });
+        entry(request, response)
+    }
+
+    fn load(entry: Value) -> ArchivedRequest {
+        let har = json!({ "log": {
+            "version": "1.2",
+            "creator": { "name": "Talkboy", "version": "0.1.0" },
+            "entries": [entry]
+        }});
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recorded.json");
+        fs::write(&path, har.to_string()).unwrap();
+        let loader = HarLoader::new(Logger::root(Discard, o!()));
+        loader.load(&path).unwrap().remove(0)
+    }
+
+    fn service(transactions: Vec<ArchivedRequest>) -> PlaybackService {
+        PlaybackService::new(
+            Logger::root(Discard, o!()),
+            TrailerSlot::default(),
+            Arc::new(RwLock::new(transactions)),
+            Arc::new(Latency::new(0, DelayOptions::None)),
+            WebSocketOptions::default(),
+            None,
+            None,
+        )
+    }
+
+    /// Status, headers and body of the answer to `request`
+    fn get(
+        service: &mut PlaybackService,
+        request: Request<Body>,
+    ) -> (StatusCode, HeaderMap, Bytes) {
+        let answer = service.call(request).and_then(|response| {
+            let (parts, mut body): (_, ReplayBody) = response.into_parts();
+            stream::poll_fn(move || body.poll_data())
+                .map_err(|e| failure::err_msg(e.to_string()))
+                .concat2()
+                .map(move |body| (parts.status, parts.headers, body.into_bytes()))
+        });
+        Runtime::new().unwrap().block_on(answer).unwrap()
+    }
+
+    fn request(path: &str) -> Request<Body> {
+        Request::get(path).body(Body::empty()).unwrap()
+    }
+
+    /// A port nothing is listening on
+    fn free_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    /// An upstream answering every request with `body`
+    fn upstream(body: &'static str) -> SocketAddr {
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .serve(move || service_fn_ok(move |_| Response::new(Body::from(body))));
+        let addr = server.local_addr();
+        thread::spawn(move || current_thread::run(server.map_err(|e| panic!("{}", e))));
+        addr
+    }
+
+    /// Run the recording proxy for `config` on a thread of its own.  It's listening
+    /// by the time this returns.
+    fn record(config: ProxyServerConfig) {
+        let logger = Logger::root(Discard, o!());
+        let incoming = Listener::bind(logger.clone(), &config.socket, None).unwrap();
+        let server = Server::builder(incoming)
+            .serve(MakeProxyService::new(&logger, config))
+            .map_err(|e| panic!("{}", e));
+        thread::spawn(move || current_thread::run(server));
+    }
+
+    /// The one recording in `dir` as written and as loaded, waiting for it since it's
+    /// written once the response has been sent
+    fn recorded(dir: &Path) -> (String, Vec<ArchivedRequest>) {
+        let loader = HarLoader::new(Logger::root(Discard, o!()));
+        for _ in 0..50 {
+            let files: Vec<_> = fs::read_dir(dir)
+                .map(|d| d.map(|e| e.unwrap().path()).collect())
+                .unwrap_or_default();
+            assert!(files.len() <= 1);
+            if let Some(Ok(recording)) = files.first().map(|f| loader.load(f)) {
+                return (fs::read_to_string(&files[0]).unwrap(), recording);
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("Nothing recorded in {}", dir.display());
+    }
+
+    /// A recording answered with `body` in three chunks, received over 100ms of a
+    /// 150ms exchange
+    fn chunked_recording(body: &str) -> ArchivedRequest {
+        let mut entry = recording_entry(&[], body);
+        entry["time"] = json!(150);
+        entry["comment"] = json!(json!({ "chunks": [
+            { "offset": 0, "size": 4 },
+            { "offset": 50, "size": 4 },
+            { "offset": 100, "size": 4 }
+        ]})
+        .to_string());
+        load(entry)
+    }
+
+    #[test]
+    fn test_timed_body() {
+        // Twice as long as what was received, as if it had been decoded since
+        let recorded = chunked_recording("one two three four five ");
+        let (parts, _) = request("/recorded").into_parts();
+        let delay = DelayOptions::Original { scale: 1.0 };
+        let response = recorded
+            .hyper_response(&parts, b"", &delay, None, None)
+            .unwrap();
+        let mut body = response.into_body();
+        let started = Instant::now();
+        let chunks = stream::poll_fn(move || body.poll_data())
+            .map(move |chunk| (chunk.into_bytes(), started.elapsed()))
+            .collect();
+        let chunks = Runtime::new().unwrap().block_on(chunks).unwrap();
+        let sizes: Vec<_> = chunks.iter().map(|(c, _)| c.len()).collect();
+        assert_eq!(vec![8, 8, 8], sizes);
+        assert!(chunks[1].1 >= Duration::from_millis(50));
+        assert!(chunks[2].1 >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_delay_leaves_body_time_to_chunks() {
+        let recorded = chunked_recording("one two three ");
+        let latency = Latency::new(0, DelayOptions::None);
+        let before = Instant::now();
+        let delay = recorded.delay(&DelayOptions::Original { scale: 0.5 }, &latency);
+        // 50ms until the headers, at half speed
+        let wait = delay.deadline() - before;
+        assert!(wait >= Duration::from_millis(25) && wait < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_grpc_without_descriptors() {
+        let mut request_body = Vec::new();
+        grpc::frame(false, b"\x0a\x05hello", &mut request_body);
+        let mut response_body = Vec::new();
+        grpc::frame(false, b"\x0a\x0bhello world", &mut response_body);
+        let request = json!({
+            "method": "POST", "url": "http://example.com/helloworld.Greeter/SayHello",
+            "httpVersion": "HTTP/2", "cookies": [], "queryString": [],
+            "headers": [{ "name": "content-type", "value": "application/grpc" }],
+            "postData": {
+                "mimeType": "application/grpc",
+                "text": base64::encode(&request_body),
+                "comment": "base64"
+            },
+            "headersSize": -1, "bodySize": -1
+        });
+        let response = json!({
+            "status": 200, "statusText": "OK", "httpVersion": "HTTP/2", "cookies": [],
+            "headers": [{ "name": "content-type", "value": "application/grpc" }],
+            "content": {
+                "size": response_body.len(), "mimeType": "application/grpc",
+                "text": base64::encode(&response_body), "encoding": "base64"
+            },
+            "redirectURL": "", "headersSize": -1, "bodySize": -1
+        });
+        let mut entry = entry(request, response);
+        // Decoded when it was recorded, with descriptors playback doesn't have
+        entry["comment"] = json!({ "grpc": {
+            "service": "helloworld.Greeter",
+            "method": "SayHello",
+            "request": [{ "json": { "name": "hello" } }],
+            "response": [{ "json": { "message": "hello world" } }]
+        }})
+        .to_string()
+        .into();
+        let mut service = service(vec![load(entry)]);
+        let call = Request::post("/helloworld.Greeter/SayHello")
+            .header("content-type", "application/grpc")
+            .body(Body::from(request_body))
+            .unwrap();
+        let (status, _, body) = get(&mut service, call);
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(response_body, body);
+    }
+
+    #[test]
+    fn test_forwarded_headers_round_trip() {
+        let mut rt = Runtime::new().unwrap();
+        let upstream = format!("http://{}", upstream("items"));
+        let forwarded = |uri: String, client: &str| {
+            Request::get(uri)
+                .header("x-forwarded-for", client)
+                .header("forwarded", format!("for={}", client))
+                .body(Body::empty())
+                .unwrap()
+        };
+        for &mode in &[ForwardedHeaders::Add, ForwardedHeaders::Strip] {
+            let dir = tempfile::tempdir().unwrap();
+            let listen = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+            let uri = upstream.parse().unwrap();
+            let mut config = ProxyServerConfig::new("forwarded", listen, uri, dir.path(), vec![]);
+            config.forwarded_headers = mode;
+            record(config);
+
+            let sent = Client::new()
+                .request(forwarded(format!("http://{}/items", listen), "10.0.0.1"))
+                .and_then(|response| response.into_body().concat2());
+            assert_eq!("items", rt.block_on(sent).unwrap().into_bytes());
+            let (har, recording) = recorded(&dir.path().join("forwarded"));
+            match mode {
+                ForwardedHeaders::Add => assert!(har.contains("10.0.0.1, 127.0.0.1")),
+                _ => assert!(!har.contains("10.0.0.1")),
+            }
+
+            // Played back to a client behind other proxies
+            let mut service = service(recording);
+            let (status, _, body) = get(&mut service, forwarded("/items".into(), "192.168.1.20"));
+            assert_eq!((StatusCode::OK, "items".into()), (status, body));
+        }
+    }
+
+    #[test]
+    fn test_recorded_bandwidth() {
+        // 10 bytes received over the last 10ms of 20
+        let recorded = recording(&[], "0123456789");
+        assert_eq!(Some(1000), recorded.bytes_per_second(Bandwidth::Recorded));
+        // Recordings that don't say fall back to the whole exchange
+        let mut entry = recording_entry(&[], "0123456789");
+        entry["timings"]["receive"] = json!(-1);
+        assert_eq!(Some(500), load(entry).bytes_per_second(Bandwidth::Recorded));
+    }
+
+    #[test]
+    fn test_upstream_failure_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let listen = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+        let upstream = format!("http://127.0.0.1:{}", free_port()).parse().unwrap();
+        let mut config = ProxyServerConfig::new("failures", listen, upstream, dir.path(), vec![]);
+        config.retries = 1;
+        config.record_failures = true;
+        record(config);
+
+        let post = || {
+            Request::post(format!("http://{}/items", listen))
+                .body(Body::from("upload"))
+                .unwrap()
+        };
+        let sent = Client::new().request(post()).and_then(|response| {
+            let (parts, body) = response.into_parts();
+            body.concat2()
+                .map(move |body| (parts.status, body.into_bytes()))
+        });
+        let (status, failure) = Runtime::new().unwrap().block_on(sent).unwrap();
+        assert_eq!(StatusCode::BAD_GATEWAY, status);
+
+        let (har, recording) = recorded(&dir.path().join("failures"));
+        // The body was read back from the spool for the retry, then recorded
+        assert!(har.contains("\"upload\""));
+        let mut service = service(recording);
+        let (status, _, body) = get(&mut service, post());
+        assert_eq!((StatusCode::BAD_GATEWAY, failure), (status, body));
+    }
+
+    #[test]
+    fn test_fallback_respond() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-talkboy", HeaderValue::from_static("miss"));
+        let mut service =
+            service(vec![recording(&[], "found")]).fallback(Arc::new(Fallback::Respond {
+                status: StatusCode::NOT_IMPLEMENTED,
+                headers,
+                body: Bytes::from("No recording"),
+            }));
+        let (status, headers, body) = get(&mut service, request("/missing"));
+        assert_eq!(StatusCode::NOT_IMPLEMENTED, status);
+        assert_eq!("miss", headers["x-talkboy"]);
+        assert_eq!("No recording", body);
+
+        let (status, _, body) = get(&mut service, request("/recorded"));
+        assert_eq!((StatusCode::OK, "found".into()), (status, body));
+    }
+
+    #[test]
+    fn test_fallback_recording() {
+        let fallback = recording(&[("content-type", "text/plain")], "fallback");
+        let mut recorded =
+            service(Vec::new()).fallback(Arc::new(Fallback::Recording(Box::new(fallback))));
+        let (status, headers, body) = get(&mut recorded, request("/anything?at=all"));
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("text/plain", headers["content-type"]);
+        assert_eq!("fallback", body);
+
+        let mut service = service(Vec::new());
+        let (status, _, _) = get(&mut service, request("/anything"));
+        assert_eq!(StatusCode::NOT_FOUND, status);
+    }
+
+    #[test]
+    fn test_refresh_dates_not_modified() {
+        let headers = [
+            ("date", "Fri, 01 Feb 2019 12:00:00 GMT"),
+            ("last-modified", "Thu, 31 Jan 2019 12:00:00 GMT"),
+        ];
+        let mut service = service(vec![recording(&headers, "cached")]).refresh_dates(true);
+        let (status, headers, _) = get(&mut service, request("/recorded"));
+        assert_eq!(StatusCode::OK, status);
+        assert_ne!("Thu, 31 Jan 2019 12:00:00 GMT", headers["last-modified"]);
+
+        // The cached copy stays good as playback goes on
+        thread::sleep(Duration::from_millis(1100));
+        let conditional = Request::get("/recorded")
+            .header("if-modified-since", headers["last-modified"].clone())
+            .body(Body::empty())
+            .unwrap();
+        let (status, again, body) = get(&mut service, conditional);
+        assert_eq!(StatusCode::NOT_MODIFIED, status);
+        assert_ne!(headers["date"], again["date"]);
+        assert!(body.is_empty());
+    }
+}
@@ -1,21 +1,46 @@
-use crate::archive::HarSession;
-use crate::config::ProxyServerConfig;
+use crate::archive::{
+    ChunkTiming, ContentEncoding, Direction, GrpcCall, HarSession, RouteTag, SpooledBody,
+    WebSocketMessage, IGNORED_DIR,
+};
+use crate::config::{ForwardedHeaders, Protocol, ProxyServerConfig};
+use crate::grpc::{self, Descriptors};
+use crate::listener::{Listener, ServerStream};
+use crate::rewrite::{self, BodyRewrite, Replacer};
+use crate::route::{PathMatcher, Route};
+use crate::tee::{self, SharedSpool, TeeBody};
+use crate::trailers::{TrailerConnector, TrailerIo, TrailerSlot};
+use crate::upstream::{UpstreamConnector, UpstreamProxy};
+use crate::websocket::{self, FrameCodec};
 use failure::Error;
-use futures::future::{self, FutureResult};
-use futures::{Future, Stream};
-use hyper::client::{Client as HyperClient, HttpConnector};
+use futures::future::{self, Either, FutureResult, Loop};
+use futures::sync::oneshot;
+use futures::{try_ready, Async, Future, Stream};
+use hyper::body::Payload;
+use hyper::client::Client as HyperClient;
 use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::http::request::Parts as RequestParts;
+use hyper::http::response::Parts as ResponseParts;
 use hyper::http::uri::Authority;
 use hyper::service::{MakeService, Service};
-use hyper::{Body, Chunk, Request, Response, Server, Uri};
-use hyper_rustls::HttpsConnector;
+use hyper::upgrade::Upgraded;
+use hyper::{rt, Body, Method, Request, Response, Server, StatusCode, Uri, Version};
 use slog::FnValue;
 use slog::Logger;
-use std::path::{Path, PathBuf};
+use std::io;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::codec::Decoder;
+use tokio::io::AsyncRead;
+use tokio::timer::Timeout;
 
-type Client = HyperClient<HttpsConnector<HttpConnector>, Body>;
+type Client = HyperClient<TrailerConnector<UpstreamConnector>, TeeBody>;
 
 // hop-by-hop headers as according to http://www.w3.org/Protocols/rfc2616/rfc2616-sec13.html
+// `Trailer` is listed there as "Trailers", but it describes the message and is end-to-end
+// (https://tools.ietf.org/html/rfc7230#section-4.4)
 lazy_static! {
     static ref HOP_HEADERS: Vec<HeaderName> = vec![
         header::CONNECTION,
@@ -23,10 +48,17 @@ lazy_static! {
         header::PROXY_AUTHENTICATE,
         header::PROXY_AUTHORIZATION,
         header::TE,
-        HeaderName::from_static("trailers"),
         header::TRANSFER_ENCODING,
         header::UPGRADE,
     ];
+    /// Describe the client to the upstream; never part of a recording's hash or of
+    /// what playback matches on
+    static ref FORWARDED_HEADERS: Vec<HeaderName> = vec![
+        header::FORWARDED,
+        HeaderName::from_static("x-forwarded-for"),
+        HeaderName::from_static("x-forwarded-host"),
+        HeaderName::from_static("x-forwarded-proto"),
+    ];
 }
 
 #[derive(Debug, Fail)]
@@ -35,21 +67,92 @@ pub struct AuthorityError {
     uri: Uri,
 }
 
+/// Why no response came back from the upstream
+#[derive(Debug, Fail)]
+pub enum UpstreamFailure {
+    #[fail(display = "Upstream didn't respond within {}ms", _0)]
+    TimedOut(u128),
+    #[fail(display = "{}", _0)]
+    Failed(#[cause] hyper::Error),
+    #[fail(display = "Request timer failed: {}", _0)]
+    Timer(#[cause] tokio::timer::Error),
+    #[fail(display = "Couldn't read the request body back from its spool: {}", _0)]
+    Spool(#[cause] io::Error),
+}
+
+impl UpstreamFailure {
+    /// 504 for timeouts, connecting included, and 502 for everything else
+    fn status(&self) -> StatusCode {
+        let connect_timed_out = |e: &hyper::Error| {
+            e.cause2()
+                .and_then(|c| c.downcast_ref::<io::Error>())
+                .map(|c| c.kind() == io::ErrorKind::TimedOut)
+                .unwrap_or(false)
+        };
+        match self {
+            UpstreamFailure::TimedOut(_) => StatusCode::GATEWAY_TIMEOUT,
+            UpstreamFailure::Failed(e) if connect_timed_out(e) => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// Requests that never reached the upstream can always be sent again, others
+    /// only if sending them twice does no harm
+    fn is_retryable(&self, method: &Method) -> bool {
+        match self {
+            UpstreamFailure::Failed(e) if e.is_connect() => true,
+            UpstreamFailure::Timer(_) | UpstreamFailure::Spool(_) => false,
+            _ => [
+                Method::GET,
+                Method::HEAD,
+                Method::PUT,
+                Method::DELETE,
+                Method::OPTIONS,
+                Method::TRACE,
+            ]
+            .contains(method),
+        }
+    }
+
+    /// Stands in for the response the upstream didn't send
+    fn response(&self, version: Version) -> Response<Body> {
+        let message = format!("{}\n", self);
+        let mut res = Response::new(Body::empty());
+        *res.status_mut() = self.status();
+        *res.version_mut() = version;
+        let headers = res.headers_mut();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; charset=utf-8"),
+        );
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(message.len()));
+        res.extensions_mut().insert(UpstreamError(self.to_string()));
+        *res.body_mut() = Body::from(message);
+        res
+    }
+}
+
+/// Marks a response made up by `UpstreamFailure::response`
+struct UpstreamError(String);
+
 pub struct MakeProxyService {
     logger: Logger,
-    proxy_for: Uri,
+    config: Arc<ProxyServerConfig>,
     client: Client,
+    grpc_client: Client,
     archive_path: PathBuf,
-    ignored_status_codes: Vec<u16>,
 }
 
 pub struct ProxyService {
     logger: Logger,
-    proxy_for: Uri,
+    config: Arc<ProxyServerConfig>,
+    trailer_slot: TrailerSlot,
     host_header: HeaderValue,
     client: Client,
+    /// gRPC only works over HTTP/2, whatever the upstream protocol is set to
+    grpc_client: Client,
     archive_path: PathBuf,
-    ignored_status_codes: Vec<u16>,
+    remote_addr: Option<SocketAddr>,
 }
 
 fn remove_hop_headers(headers: &mut HeaderMap) {
@@ -58,23 +161,80 @@ fn remove_hop_headers(headers: &mut HeaderMap) {
     }
 }
 
+/// Whether the `TE` header says trailers are accepted
+fn accepts_trailers(headers: &HeaderMap) -> bool {
+    headers.get_all(header::TE).iter().any(|v| {
+        v.to_str()
+            .map(|v| {
+                v.split(',')
+                    .any(|t| t.trim().eq_ignore_ascii_case("trailers"))
+            })
+            .unwrap_or(false)
+    })
+}
+
 fn extract_authority(uri: &Uri) -> Result<Authority, AuthorityError> {
     uri.authority_part()
         .cloned()
         .ok_or_else(|| AuthorityError { uri: uri.clone() })
 }
 
-fn calculate_target_uri<B>(requested: &Uri, proxied: &Uri) -> Result<Uri, Error> {
-    let authority = extract_authority(proxied)?;
+/// The upstream URI for a request, and the route that picked the upstream unless
+/// it's the default one
+fn calculate_target_uri<'a>(
+    requested: &Uri,
+    proxied: &Uri,
+    routes: &'a [Route],
+) -> Result<(Uri, Option<&'a Route>), Error> {
+    let routed = routes
+        .iter()
+        .find_map(|r| r.upstream_path(requested.path()).map(|p| (r, p)));
+    let (upstream, route, path) = match routed {
+        Some((r, p)) => (&r.upstream, Some(r), p),
+        None => (proxied, None, requested.path().to_string()),
+    };
+    let authority = extract_authority(upstream)?;
     let mut builder = Uri::builder();
     builder
-        .scheme(proxied.scheme_str().unwrap_or("http"))
-        .authority(authority.clone());
-    if let Some(p) = requested.path_and_query() {
-        builder.path_and_query(p.clone());
+        .scheme(upstream.scheme_str().unwrap_or("http"))
+        .authority(authority);
+    match requested.query() {
+        Some(q) => builder.path_and_query(format!("{}?{}", path, q).as_str()),
+        None => builder.path_and_query(path.as_str()),
+    };
+
+    Ok((builder.build()?, route))
+}
+
+// `Parts` can't be cloned directly because of its `Extensions`, which we don't record anyway
+fn copy_request_head(head: &RequestParts) -> RequestParts {
+    let mut req = Request::new(());
+    *req.method_mut() = head.method.clone();
+    *req.uri_mut() = head.uri.clone();
+    *req.version_mut() = head.version;
+    *req.headers_mut() = head.headers.clone();
+    req.into_parts().0
+}
+
+/// Adds `value` to the comma-separated list in the `name` header
+fn append_to_list(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    let mut list: Vec<&str> = headers
+        .get_all(&name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    list.push(value);
+    if let Ok(v) = HeaderValue::from_str(&list.join(", ")) {
+        headers.insert(name, v);
     }
+}
 
-    Ok(builder.build()?)
+fn copy_response_head(head: &ResponseParts) -> ResponseParts {
+    let mut res = Response::new(());
+    *res.status_mut() = head.status;
+    *res.version_mut() = head.version;
+    *res.headers_mut() = head.headers.clone();
+    res.into_parts().0
 }
 
 fn create_proxied_response<B>(mut response: Response<B>) -> Response<B> {
@@ -82,25 +242,179 @@ fn create_proxied_response<B>(mut response: Response<B>) -> Response<B> {
     response
 }
 
+/// Maps the origins of the upstreams to `listener`, putting back the prefixes that
+/// routes strip
+fn origin_replacer(config: &ProxyServerConfig, listener: &str) -> Replacer {
+    let mut pairs = Vec::new();
+    if let Some(o) = rewrite::origin(&config.proxy_for) {
+        pairs.push((o, listener.to_string()));
+    }
+    for route in &config.routes {
+        if let Some(o) = rewrite::origin(&route.upstream) {
+            let to = match (&route.matcher, route.strip_prefix) {
+                (PathMatcher::Prefix(p), true) => {
+                    format!("{}{}", listener, p.trim_end_matches('/'))
+                }
+                _ => listener.to_string(),
+            };
+            pairs.push((o, to));
+        }
+    }
+    Replacer::new(pairs)
+}
+
+/// Rewrites the headers of a response on its way to the client, returning the
+/// rewrite to apply to its body if that should be rewritten too
+fn rewrite_response(
+    config: &ProxyServerConfig,
+    replacer: Option<Replacer>,
+    headers: &mut HeaderMap,
+) -> Option<BodyRewrite> {
+    let rewrite = config.rewrite.as_ref()?;
+    let replacer = replacer?;
+    rewrite.rewrite_headers(&replacer, headers);
+    // Compressed bodies are decoded to be rewritten, unless the coding is unknown
+    let encoding = ContentEncoding::from_headers(headers);
+    if !rewrite.rewrites_body(headers)
+        || (encoding.is_none() && headers.contains_key(header::CONTENT_ENCODING))
+    {
+        return None;
+    }
+    headers.remove(header::CONTENT_LENGTH);
+    Some(BodyRewrite::new(replacer, encoding))
+}
+
+/// The version to send a request upstream in.  hyper only sends HTTP/2 requests on
+/// a client that always speaks HTTP/2, otherwise the version is negotiated per
+/// connection.
+fn upstream_version(protocol: Protocol, is_grpc: bool, version: Version) -> Version {
+    match (protocol, version) {
+        _ if is_grpc => Version::HTTP_2,
+        (Protocol::Http2, _) => Version::HTTP_2,
+        (_, Version::HTTP_10) => Version::HTTP_10,
+        _ => Version::HTTP_11,
+    }
+}
+
+/// Requests sent to an upstream proxy as they are carry its credentials, which
+/// are left out of the recording.  HTTPS and HTTP/2 requests go through a
+/// `CONNECT` tunnel instead, which carries them itself.
+fn authorize_upstream_proxy(proxy: Option<&UpstreamProxy>, head: &mut RequestParts) {
+    let proxy = match proxy {
+        Some(p) => p,
+        None => return,
+    };
+    let forwarded = head.uri.scheme_str() == Some("http") && head.version != Version::HTTP_2;
+    if forwarded && proxy.applies_to(head.uri.host().unwrap_or_default()) {
+        if let Some(auth) = proxy.authorization() {
+            head.headers
+                .insert(header::PROXY_AUTHORIZATION, auth.clone());
+        }
+    }
+}
+
+/// Send a request upstream, giving up on it after `timeout`
+fn send_timed(
+    client: &Client,
+    req: Request<TeeBody>,
+    timeout: Option<Duration>,
+) -> Box<dyn Future<Item = Response<Body>, Error = UpstreamFailure> + Send> {
+    let sending = client.request(req).map_err(UpstreamFailure::Failed);
+    match timeout {
+        Some(t) => Box::new(Timeout::new(sending, t).map_err(move |e| {
+            if e.is_elapsed() {
+                UpstreamFailure::TimedOut(t.as_millis())
+            } else if e.is_timer() {
+                UpstreamFailure::Timer(e.into_timer().unwrap())
+            } else {
+                e.into_inner().unwrap()
+            }
+        })),
+        None => Box::new(sending),
+    }
+}
+
+/// Send a request upstream, giving up on it after the request timeout.  Failed
+/// requests are retried from the spool, which needs the rest of the body read into
+/// it first; so does recording failures, since the upstream may not have read it all.
+/// The first attempt streams the body as it arrives either way.
+fn send_upstream(
+    client: Client,
+    head: RequestParts,
+    body: Body,
+    spool: SharedSpool,
+    config: &ProxyServerConfig,
+    logger: Logger,
+) -> Box<dyn Future<Item = Response<Body>, Error = UpstreamFailure> + Send> {
+    let timeout = config.request_timeout;
+    let send = move |req| send_timed(&client, req, timeout);
+    if config.retries == 0 && !config.record_failures {
+        return send(Request::from_parts(head, TeeBody::spooled(body, spool)));
+    }
+    let retries = config.retries;
+    let (leftover, rest) = oneshot::channel();
+    let body = TeeBody::spooled(body, spool.clone()).leftover(leftover);
+    let first = send(Request::from_parts(copy_request_head(&head), body));
+    let sending = first.or_else(move |e| {
+        // Sent once hyper has dropped the failed request, with the body in it
+        rest.then(move |rest| {
+            let mut rest = rest
+                .ok()
+                .and_then(|r| r)
+                .map(|body| TeeBody::spooled(body, spool.clone()));
+            future::poll_fn(move || {
+                if let Some(ref mut body) = rest {
+                    while try_ready!(body.poll_data()).is_some() {}
+                }
+                Ok(Async::Ready(()))
+            })
+            .map_err(UpstreamFailure::Failed)
+            .and_then(move |()| {
+                future::loop_fn((0, e), move |(attempt, e)| {
+                    if attempt >= retries || !e.is_retryable(&head.method) {
+                        return Either::A(future::err(e));
+                    }
+                    warn!(logger, "Retrying: {}", e; "attempt" => attempt + 1);
+                    let body = match spool.lock().unwrap().replay() {
+                        Ok(body) => TeeBody::passthrough(body),
+                        Err(e) => return Either::A(future::err(UpstreamFailure::Spool(e))),
+                    };
+                    let req = Request::from_parts(copy_request_head(&head), body);
+                    Either::B(send(req).then(move |r| match r {
+                        Ok(res) => Ok(Loop::Break(res)),
+                        Err(e) => Ok(Loop::Continue((attempt + 1, e))),
+                    }))
+                })
+            })
+        })
+    });
+    Box::new(sending)
+}
+
+fn build_client(protocol: Protocol, config: &ProxyServerConfig) -> Client {
+    let mut tls = config.upstream_tls.clone();
+    tls.set_protocols(&protocol.alpn());
+    let connector = UpstreamConnector::new(tls, config.server_name.clone())
+        .insecure(config.insecure)
+        .through_proxy(config.upstream_proxy.clone(), protocol == Protocol::Http2)
+        .connect_timeout(config.connect_timeout);
+    HyperClient::builder()
+        .http2_only(protocol == Protocol::Http2)
+        .build(TrailerConnector::new(connector))
+}
+
 impl MakeProxyService {
-    pub fn new<S: Into<String>, P: AsRef<Path>, V: Into<Vec<u16>>>(
-        logger: &Logger,
-        proxy_for: Uri,
-        name: S,
-        archive_path: P,
-        ignored_status_codes: V,
-    ) -> MakeProxyService {
-        let name = name.into();
-        let uri = format!("{}", proxy_for);
+    pub fn new(logger: &Logger, config: ProxyServerConfig) -> MakeProxyService {
+        let uri = format!("{}", config.proxy_for);
         let logger = logger.new(o!("for" => uri));
-        let https = HttpsConnector::new(4);
-        let client: Client = HyperClient::builder().build(https);
+        let client = build_client(config.upstream_protocol, &config);
+        let grpc_client = build_client(Protocol::Http2, &config);
         MakeProxyService {
             logger,
-            proxy_for,
             client,
-            archive_path: archive_path.as_ref().join(name),
-            ignored_status_codes: ignored_status_codes.into(),
+            grpc_client,
+            archive_path: config.archive_path.join(&config.name),
+            config: Arc::new(config),
         }
     }
 }
@@ -108,19 +422,65 @@ impl MakeProxyService {
 impl ProxyService {
     fn new(
         logger: Logger,
-        proxy_for: Uri,
+        config: Arc<ProxyServerConfig>,
+        trailer_slot: TrailerSlot,
         host_header: HeaderValue,
         client: Client,
+        grpc_client: Client,
         archive_path: PathBuf,
-        ignored_status_codes: Vec<u16>,
     ) -> ProxyService {
         ProxyService {
             logger,
-            proxy_for,
+            config,
+            trailer_slot,
             client,
+            grpc_client,
             host_header,
             archive_path,
-            ignored_status_codes,
+            remote_addr: None,
+        }
+    }
+
+    /// The address of the client the service answers
+    fn remote_addr(mut self, addr: SocketAddr) -> ProxyService {
+        self.remote_addr = Some(addr);
+        self
+    }
+
+    /// Tells the upstream who the client was and how it reached talkboy, after what
+    /// any proxies in front of talkboy said
+    fn add_forwarded_headers(&self, headers: &mut HeaderMap, host: Option<HeaderValue>) {
+        let addr = match self.remote_addr {
+            Some(a) => a.ip(),
+            None => return,
+        };
+        let proto = if self.config.tls.is_some() {
+            "https"
+        } else {
+            "http"
+        };
+        let node = match addr {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+        };
+        let mut element = format!("for={};proto={}", node, proto);
+        if let Some(h) = host.as_ref().and_then(|h| h.to_str().ok()) {
+            element.push_str(&format!(";host=\"{}\"", h));
+        }
+        append_to_list(headers, header::FORWARDED, &element);
+        append_to_list(
+            headers,
+            HeaderName::from_static("x-forwarded-for"),
+            &addr.to_string(),
+        );
+        // A proxy in front of talkboy knows better how the request started out
+        let proto_name = HeaderName::from_static("x-forwarded-proto");
+        if !headers.contains_key(&proto_name) {
+            headers.insert(proto_name, HeaderValue::from_static(proto));
+        }
+        let host_name = HeaderName::from_static("x-forwarded-host");
+        if let (false, Some(h)) = (headers.contains_key(&host_name), host) {
+            headers.insert(host_name, h);
         }
     }
 
@@ -130,14 +490,266 @@ impl ProxyService {
         target: Uri,
         host_header: HeaderValue,
     ) -> Request<B> {
+        let is_grpc = grpc::is_grpc(req.headers());
+        let trailers = accepts_trailers(req.headers());
         remove_hop_headers(req.headers_mut());
+        match self.config.forwarded_headers {
+            ForwardedHeaders::Add => {
+                // HTTP/2 clients send the host as the `:authority` instead
+                let client_host = req.headers().get(header::HOST).cloned().or_else(|| {
+                    req.uri()
+                        .authority_part()
+                        .and_then(|a| a.as_str().parse().ok())
+                });
+                self.add_forwarded_headers(req.headers_mut(), client_host);
+            }
+            ForwardedHeaders::Preserve => {}
+            ForwardedHeaders::Strip => {
+                for h in FORWARDED_HEADERS.iter() {
+                    req.headers_mut().remove(h);
+                }
+            }
+        }
         req.headers_mut().insert(header::HOST, host_header);
         *req.uri_mut() = target;
+        *req.version_mut() =
+            upstream_version(self.config.upstream_protocol, is_grpc, req.version());
+        if trailers || is_grpc {
+            // Trailers are recorded (and passed on) whatever the client, so keep
+            // telling the upstream they're welcome; gRPC servers insist on it
+            req.headers_mut()
+                .insert(header::TE, HeaderValue::from_static("trailers"));
+        }
+        if is_grpc {
+            // Compressed messages would be unreadable in the recording
+            req.headers_mut()
+                .remove(HeaderName::from_static("grpc-accept-encoding"));
+        }
         req
     }
 }
 
-impl<C> MakeService<C> for MakeProxyService {
+/// Everything needed to write out an exchange once its response body has been streamed
+struct Recording {
+    har: HarSession,
+    request_head: RequestParts,
+    request_spool: SharedSpool,
+    response_head: ResponseParts,
+    response_spool: SharedSpool,
+    archive_path: PathBuf,
+    file_name_part: String,
+    grpc: Option<GrpcCall>,
+    descriptors: Option<Descriptors>,
+    route: Option<RouteTag>,
+    upstream_error: Option<String>,
+}
+
+/// Run `f` on the runtime's blocking threads, so writing out a recording doesn't hold
+/// up the connection that finished it.  Off the thread pool it just runs.
+fn spawn_blocking<F: FnOnce() + Send + 'static>(f: F) {
+    let mut f = Some(f);
+    rt::spawn(future::poll_fn(move || {
+        match tokio_threadpool::blocking(|| f.take().map_or((), |f| f())) {
+            Ok(ready) => Ok(ready),
+            Err(_) => {
+                if let Some(f) = f.take() {
+                    f();
+                }
+                Ok(Async::Ready(()))
+            }
+        }
+    }));
+}
+
+impl Recording {
+    fn write(mut self, logger: &Logger) -> Result<PathBuf, Error> {
+        let mut request_body = self.request_spool.lock().unwrap().take()?;
+        let (mut response_body, chunks, trailers, ended) = {
+            let mut spool = self.response_spool.lock().unwrap();
+            let ended = spool.ended();
+            (
+                spool.take()?,
+                spool.take_chunks(),
+                spool.take_trailers(),
+                ended,
+            )
+        };
+        if let Some(mut call) = self.grpc.take() {
+            // Messages are converted as a whole, so the bodies are read into memory
+            let request_bytes = request_body.into_bytes()?;
+            let response_bytes = response_body.into_bytes()?;
+            let descriptors = self.descriptors.as_ref();
+            let messages = call
+                .messages(&request_bytes, true, descriptors)
+                .and_then(|req| Ok((req, call.messages(&response_bytes, false, descriptors)?)));
+            match messages {
+                Ok((request, response)) => {
+                    call.request = request;
+                    call.response = response;
+                    self.har.record_grpc(call);
+                }
+                Err(e) => warn!(logger, "Couldn't read gRPC messages: {}", e),
+            }
+            request_body = SpooledBody::Memory(request_bytes);
+            response_body = SpooledBody::Memory(response_bytes);
+        }
+        if let Some(ref t) = trailers {
+            self.har.record_trailers(t);
+        }
+        if let Some(ended) = ended {
+            self.har.record_receive(ended);
+        }
+        if let Some(route) = self.route.take() {
+            self.har.record_route(route);
+        }
+        if let Some(e) = self.upstream_error.take() {
+            self.har.record_upstream_error(e);
+        }
+        self.har.record_request(&self.request_head, request_body)?;
+        self.har
+            .record_response(&self.response_head, response_body)?;
+        // Only streamed responses have a timing shape worth keeping
+        let streamed = !self
+            .response_head
+            .headers
+            .contains_key(header::CONTENT_LENGTH);
+        if streamed && chunks.len() > 1 {
+            let chunks = chunks
+                .into_iter()
+                .map(|(offset, size)| ChunkTiming {
+                    offset: offset.as_secs() * 1000 + u64::from(offset.subsec_millis()),
+                    size,
+                })
+                .collect();
+            self.har.record_chunks(chunks);
+        }
+        self.har.commit()?;
+        // The quarantine directory is only made once something goes in it
+        std::fs::create_dir_all(&self.archive_path)?;
+        trace!(
+            logger,
+            "Writing file to dir {:?}, name fragment {}",
+            &self.archive_path,
+            self.file_name_part
+        );
+        self.har
+            .write_to_dir(&self.archive_path, &self.file_name_part)
+    }
+}
+
+impl ProxyService {
+    /// Forward a WebSocket handshake and, once both sides have switched protocols,
+    /// relay frames between them.  The exchange is written out when the connection closes.
+    fn proxy_websocket(
+        &self,
+        req: Request<Body>,
+        logger: Logger,
+        archive_path: PathBuf,
+        file_name_part: String,
+        route: Option<RouteTag>,
+        record: bool,
+    ) -> <Self as Service>::Future {
+        let (mut head, body) = req.into_parts();
+        let client_upgrade = body.on_upgrade();
+        let request_head = copy_request_head(&head);
+        authorize_upstream_proxy(self.config.upstream_proxy.as_ref(), &mut head);
+        let req = Request::from_parts(head, TeeBody::passthrough(Body::empty()));
+        let mut har = HarSession::new();
+        info!(logger, "Sending WebSocket handshake");
+        let err_logger = logger.new(o!("area" => "client-error"));
+        let max_frame_size = self.config.max_frame_size;
+        har.start_session();
+        let fut = self
+            .client
+            .request(req)
+            .map_err(move |e| {
+                error!(err_logger, "{}", e);
+                Error::from(e)
+            })
+            .map(move |resp| {
+                let res_logger = logger.new(o!("status" => resp.status().as_u16()));
+                if resp.status() != StatusCode::SWITCHING_PROTOCOLS {
+                    info!(res_logger, "Upstream refused WebSocket handshake");
+                    let (head, body) = create_proxied_response(resp).into_parts();
+                    return Response::from_parts(head, TeeBody::passthrough(body));
+                }
+                let (head, body) = resp.into_parts();
+                let recording = Recording {
+                    har,
+                    request_head,
+                    request_spool: tee::shared_spool(0),
+                    response_head: copy_response_head(&head),
+                    response_spool: tee::shared_spool(0),
+                    archive_path,
+                    file_name_part,
+                    grpc: None,
+                    route,
+                    descriptors: None,
+                    upstream_error: None,
+                };
+                let err_logger = res_logger.new(o!("area" => "websocket-error"));
+                let relay = client_upgrade
+                    .join(body.on_upgrade())
+                    .map_err(Error::from)
+                    .and_then(move |(client, upstream)| {
+                        relay_frames(client, upstream, max_frame_size).map_err(Error::from)
+                    })
+                    .and_then(move |messages| {
+                        if !record {
+                            debug!(
+                                res_logger,
+                                "WebSocket closed, not recording filtered exchange"
+                            );
+                            return Ok(());
+                        }
+                        let mut recording = recording;
+                        recording.har.record_websocket(messages);
+                        let file_name = recording.write(&res_logger)?;
+                        info!(
+                        res_logger,
+                        "WebSocket closed, Wrote file"; "file_name" => FnValue(|_| {
+                            file_name.to_string_lossy().into_owned()
+                        }));
+                        Ok(())
+                    })
+                    .map_err(move |e| error!(err_logger, "{}", e));
+                rt::spawn(relay);
+                Response::from_parts(head, TeeBody::passthrough(Body::empty()))
+            });
+        Box::new(fut)
+    }
+}
+
+/// Copy frames in both directions until either side closes, collecting them as they pass
+fn relay_frames(
+    client: Upgraded,
+    upstream: Upgraded,
+    max_frame_size: usize,
+) -> impl Future<Item = Vec<WebSocketMessage>, Error = io::Error> {
+    let start = Instant::now();
+    let messages = Arc::new(Mutex::new(Vec::new()));
+    let (client_sink, client_stream) = FrameCodec::new(max_frame_size).framed(client).split();
+    let (upstream_sink, upstream_stream) = FrameCodec::new(max_frame_size).framed(upstream).split();
+    let sent = messages.clone();
+    let to_upstream = client_stream
+        .inspect(move |f| {
+            let m = f.to_message(Direction::Send, start);
+            sent.lock().unwrap().push(m);
+        })
+        .forward(upstream_sink);
+    let received = messages.clone();
+    let to_client = upstream_stream
+        .inspect(move |f| {
+            let m = f.to_message(Direction::Receive, start);
+            received.lock().unwrap().push(m);
+        })
+        .forward(client_sink);
+    to_upstream
+        .select2(to_client)
+        .then(move |_| Ok(mem::take(&mut *messages.lock().unwrap())))
+}
+
+impl<'a> MakeService<&'a TrailerIo<ServerStream>> for MakeProxyService {
     type ReqBody = <ProxyService as Service>::ReqBody;
     type ResBody = <ProxyService as Service>::ResBody;
     type Error = <ProxyService as Service>::Error;
@@ -145,8 +757,8 @@ impl<C> MakeService<C> for MakeProxyService {
     type Service = ProxyService;
     type MakeError = Error;
 
-    fn make_service(&mut self, _ctx: C) -> Self::Future {
-        let authority = match extract_authority(&self.proxy_for) {
+    fn make_service(&mut self, conn: &'a TrailerIo<ServerStream>) -> Self::Future {
+        let authority = match extract_authority(&self.config.proxy_for) {
             Ok(a) => a,
             Err(e) => {
                 error!(self.logger, "{}", e);
@@ -163,12 +775,14 @@ impl<C> MakeService<C> for MakeProxyService {
 
         let proxy = ProxyService::new(
             self.logger.clone(),
-            self.proxy_for.clone(),
+            self.config.clone(),
+            conn.slot(),
             host_header,
             self.client.clone(),
+            self.grpc_client.clone(),
             self.archive_path.clone(),
-            self.ignored_status_codes.clone(),
-        );
+        )
+        .remote_addr(conn.get_ref().remote_addr());
         trace!(self.logger, "Created ProxyService instance");
         future::ok(proxy)
     }
@@ -176,25 +790,67 @@ impl<C> MakeService<C> for MakeProxyService {
 
 impl Service for ProxyService {
     type ReqBody = Body;
-    type ResBody = Body;
+    type ResBody = TeeBody;
     type Error = Error;
     type Future = Box<dyn Future<Item = Response<Self::ResBody>, Error = Self::Error> + Send>;
     fn call(&mut self, req: Request<Self::ReqBody>) -> Self::Future {
         trace!(self.logger, "Starting request");
-        let target = match calculate_target_uri::<Self::ReqBody>(&req.uri(), &self.proxy_for) {
-            Ok(u) => u,
-            Err(e) => return Box::new(future::err(e)),
-        };
+        let config = self.config.clone();
+        let (target, route) =
+            match calculate_target_uri(req.uri(), &config.proxy_for, &config.routes) {
+                Ok(t) => t,
+                Err(e) => return Box::new(future::err(e)),
+            };
 
         trace!(self.logger, "Calculated new Uri '{}'", target);
+        let client_path = req.uri().path().to_string();
+        let host_header = route
+            .map(|r| r.host_header().clone())
+            .unwrap_or_else(|| self.host_header.clone());
+        let route_tag = route.map(|r| {
+            let requested = req.uri().path_and_query().map(|pq| pq.as_str());
+            let sent = target.path_and_query().map(|pq| pq.as_str());
+            RouteTag {
+                name: r.name.clone(),
+                path: requested.filter(|p| Some(*p) != sent).map(str::to_string),
+            }
+        });
 
-        let proxied_req = self.create_proxied_request(req, target, self.host_header.clone());
+        let client_version = req.version();
+        // hyper won't write trailers on HTTP/1, but the client connection can
+        let downstream_trailers =
+            if client_version == Version::HTTP_11 && req.method() != Method::HEAD {
+                Some(self.trailer_slot.clone())
+            } else {
+                None
+            };
+        let grpc_call = if grpc::is_grpc(req.headers()) {
+            grpc::method_path(req.uri().path()).map(|(s, m)| GrpcCall::new(s, m))
+        } else {
+            None
+        };
+        let replacer = config.rewrite.as_ref().and_then(|r| {
+            r.listener_origin(req.headers(), req.uri())
+                .map(|to| origin_replacer(&config, &to))
+        });
+        let upgrade = req.headers().get(header::UPGRADE).cloned();
+        let is_websocket = websocket::is_upgrade_request(req.headers());
+        let mut proxied_req = self.create_proxied_request(req, target, host_header);
+        if let (true, Some(upgrade)) = (is_websocket, upgrade) {
+            // Put back the hop-by-hop headers the upstream needs to switch protocols
+            let headers = proxied_req.headers_mut();
+            headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+            headers.insert(header::UPGRADE, upgrade);
+            // Compressed frames would be unreadable in the recording
+            headers.remove(header::SEC_WEBSOCKET_EXTENSIONS);
+        }
         let path = proxied_req
             .uri()
             .path_and_query()
             .map(|pq| format!("{}", pq))
             .unwrap_or_else(|| "/".to_string());
-        let path_without_query = proxied_req.uri().path().to_string();
+        // Named after the path the client used, which tells routes apart
+        let path_without_query = client_path;
         let method = proxied_req.method().to_string();
         if !self.archive_path.exists() {
             trace!(self.logger, "Creating dir {:?}", &self.archive_path);
@@ -203,88 +859,267 @@ impl Service for ProxyService {
                 Err(e) => return Box::new(future::err(e.into())),
             }
         }
-        let ignored_status_codes = self.ignored_status_codes.clone();
         let archive_path = self.archive_path.clone();
 
         let req_logger = self
             .logger
             .new(o!("path" => path.clone(), "method" => method.clone()));
 
-        let (head, body) = proxied_req.into_parts();
-        let client = self.client.clone();
-        let fut = body
-            .concat2()
-            .map_err(Error::from)
-            .and_then(move |b| {
-                let mut har = HarSession::new();
-                let body: Vec<u8> = b.into_bytes().into_iter().collect();
-                har.record_request(&head, body.clone());
-                let new_body: Body = Body::from(Chunk::from(body));
-                let req = Request::from_parts(head, new_body);
-                Ok((req, har))
+        if is_websocket {
+            let file_name_part = format!("{}.{}", method, path_without_query);
+            let record = config.filter.records(
+                proxied_req.method(),
+                &path_without_query,
+                proxied_req.headers(),
+                None,
+            );
+            return self.proxy_websocket(
+                proxied_req,
+                req_logger,
+                archive_path,
+                file_name_part,
+                route_tag,
+                record,
+            );
+        }
+
+        let (mut head, body) = proxied_req.into_parts();
+        let mut request_head = copy_request_head(&head);
+        authorize_upstream_proxy(self.config.upstream_proxy.as_ref(), &mut head);
+        let request_spool = tee::shared_spool(self.config.spool_threshold);
+        let sent_version = head.version;
+        let mut har = HarSession::new();
+        info!(req_logger, "Sending request");
+        let err_logger = req_logger.new(o!("area" => "client-error"));
+        har.start_session();
+        let client = if grpc_call.is_some() {
+            &self.grpc_client
+        } else {
+            &self.client
+        };
+        let sending = send_upstream(
+            client.clone(),
+            head,
+            body,
+            request_spool.clone(),
+            &config,
+            req_logger.clone(),
+        );
+        let fut = sending
+            .or_else(move |e| {
+                error!(err_logger, "{}", e);
+                Ok::<_, Error>(e.response(sent_version))
             })
-            .and_then(move |(req, mut har)| {
-                info!(req_logger, "Sending request");
-                let err_logger = req_logger.new(o!("area" => "client-error"));
-                har.start_session();
-                client
-                    .request(req)
-                    .map_err(move |e| {
-                        error!(err_logger, "{}", e);
-                        Error::from(e)
-                    })
-                    .and_then(move |resp| {
-                        let res = create_proxied_response(resp);
-                        let (head, body) = res.into_parts();
-                        let res_logger = req_logger.new(o!("status" => head.status.as_u16()));
-                        let err_logger = res_logger.new(o!("area" => "body-error"));
-                        let resp_err_logger = res_logger.new(o!("area" => "resp-error"));
-                        body.concat2()
-                            .map_err(move |e| {
-                                error!(err_logger, "{}", e);
-                                Error::from(e)
-                            })
-                            .and_then(move |b| {
-                                let body: Vec<u8> = b.into_bytes().into_iter().collect();
-                                har.record_response(&head, body.clone());
-                                if ignored_status_codes.contains(&head.status.as_u16()) {
-                                    info!(
-                                        res_logger,
-                                        "Ignoring response with status {}",
-                                        head.status.as_u16()
-                                    );
-                                } else {
-                                    har.commit()?;
-                                    let file_name_part =
-                                        format!("{}.{}", method, path_without_query);
-                                    trace!(
-                                        res_logger,
-                                        "Writing file to dir {:?}, name fragment {}",
-                                        &archive_path,
-                                        file_name_part
-                                    );
-                                    let filename =
-                                        har.write_to_dir(&archive_path, file_name_part)?;
-                                    info!(
-                                    res_logger,
-                                    "Received Response, Wrote file"; "file_name" => FnValue(|_| {
-                                        archive_path.join(&filename).to_string_lossy().into_owned()
-                                    }));
-                                }
-                                let new_body: Body = Body::from(Chunk::from(body));
-                                Ok(Response::from_parts(head, new_body))
-                            })
-                            .map_err(move |e| {
-                                error!(resp_err_logger, "{}", e);
-                                e
-                            })
+            .map(move |resp| {
+                let upstream_error = resp
+                    .extensions()
+                    .get::<UpstreamError>()
+                    .map(|e| e.0.clone());
+                let res = create_proxied_response(resp);
+                let (mut head, body) = res.into_parts();
+                let upstream_trailers = head.extensions.get::<TrailerSlot>().cloned();
+                // Trailers only fit after a chunked body
+                let downstream_trailers = downstream_trailers
+                    .filter(|_| !head.headers.contains_key(header::CONTENT_LENGTH));
+                let res_logger = req_logger.new(o!("status" => head.status.as_u16()));
+                // Record the version negotiated with the upstream, but answer the client
+                // in the one it used
+                request_head.version = head.version;
+                let response_head = copy_response_head(&head);
+                head.version = client_version;
+                // The recording keeps the upstream's URLs, only the client sees these
+                let body_replacer = rewrite_response(&config, replacer, &mut head.headers);
+                if upstream_error.is_some() && !config.record_failures {
+                    return Response::from_parts(head, TeeBody::passthrough(body));
+                }
+                let filtered = !config.filter.records(
+                    &request_head.method,
+                    &path_without_query,
+                    &request_head.headers,
+                    Some(&head.headers),
+                );
+                if filtered {
+                    debug!(res_logger, "Not recording filtered exchange");
+                    let body = TeeBody::passthrough(body)
+                        .trailer_slots(upstream_trailers, downstream_trailers)
+                        .rewrite(body_replacer);
+                    return Response::from_parts(head, body);
+                }
+                let ignored = config.is_ignored(head.status.as_u16());
+                if ignored && !config.quarantine_ignored {
+                    info!(
+                        res_logger,
+                        "Ignoring response with status {}",
+                        head.status.as_u16()
+                    );
+                    let body = TeeBody::passthrough(body)
+                        .trailer_slots(upstream_trailers, downstream_trailers)
+                        .rewrite(body_replacer);
+                    return Response::from_parts(head, body);
+                }
+                // Kept as evidence, where playback won't load them
+                let archive_path = if ignored {
+                    archive_path.join(IGNORED_DIR)
+                } else {
+                    archive_path
+                };
+                let response_spool = tee::shared_spool(config.spool_threshold);
+                let recording = Recording {
+                    har,
+                    request_head,
+                    request_spool,
+                    response_head,
+                    response_spool: response_spool.clone(),
+                    archive_path,
+                    file_name_part: format!("{}.{}", method, path_without_query),
+                    grpc: grpc_call,
+                    route: route_tag,
+                    descriptors: config.descriptors.clone(),
+                    upstream_error,
+                };
+                let body = TeeBody::new(body, response_spool, move || {
+                    spawn_blocking(move || {
+                        let err_logger = res_logger.new(o!("area" => "resp-error"));
+                        match recording.write(&res_logger) {
+                            Ok(file_name) => info!(
+                            res_logger,
+                            "Received Response, Wrote file"; "file_name" => FnValue(|_| {
+                                file_name.to_string_lossy().into_owned()
+                            })),
+                            Err(e) => error!(err_logger, "{}", e),
+                        }
                     })
+                })
+                .trailer_slots(upstream_trailers, downstream_trailers)
+                .rewrite(body_replacer);
+                Response::from_parts(head, body)
             });
 
         Box::new(fut)
     }
 }
 
+/// Forwards requests on some routes to their upstreams without recording them, for
+/// endpoints that playback shouldn't mock
+#[derive(Clone)]
+pub struct Passthrough {
+    routes: Arc<Vec<Route>>,
+    client: Client,
+    grpc_client: Client,
+    upstream_protocol: Protocol,
+    request_timeout: Option<Duration>,
+    upstream_proxy: Option<UpstreamProxy>,
+}
+
+impl Passthrough {
+    /// Reaches the upstreams of `routes` the way the recording proxy configured by
+    /// `config` would, with its TLS settings, upstream proxy and timeouts
+    pub fn new(routes: Vec<Route>, config: &ProxyServerConfig) -> Passthrough {
+        Passthrough {
+            routes: Arc::new(routes),
+            client: build_client(config.upstream_protocol, config),
+            grpc_client: build_client(Protocol::Http2, config),
+            upstream_protocol: config.upstream_protocol,
+            request_timeout: config.request_timeout,
+            upstream_proxy: config.upstream_proxy.clone(),
+        }
+    }
+
+    /// The route a request for `path` is forwarded through, if any
+    pub fn route(&self, path: &str) -> Option<&Route> {
+        self.routes.iter().find(|r| r.matcher.find(path).is_some())
+    }
+
+    /// Send `req` upstream like the recording proxy would, answering with a 502 or
+    /// 504 if it can't be.  WebSocket connections are relayed as they are.
+    pub fn forward(
+        &self,
+        route: &Route,
+        mut req: Request<Body>,
+        logger: Logger,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = Error> + Send> {
+        let routes = std::slice::from_ref(route);
+        let target = match calculate_target_uri(req.uri(), &route.upstream, routes) {
+            Ok((t, _)) => t,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let client_version = req.version();
+        let is_grpc = grpc::is_grpc(req.headers());
+        let trailers = accepts_trailers(req.headers());
+        let upgrade = req
+            .headers()
+            .get(header::UPGRADE)
+            .cloned()
+            .filter(|_| websocket::is_upgrade_request(req.headers()));
+        remove_hop_headers(req.headers_mut());
+        if let Some(upgrade) = upgrade.clone() {
+            // Put back the hop-by-hop headers the upstream needs to switch protocols
+            let headers = req.headers_mut();
+            headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+            headers.insert(header::UPGRADE, upgrade);
+        }
+        if trailers || is_grpc {
+            req.headers_mut()
+                .insert(header::TE, HeaderValue::from_static("trailers"));
+        }
+        req.headers_mut()
+            .insert(header::HOST, route.host_header().clone());
+        *req.uri_mut() = target;
+        *req.version_mut() = upstream_version(self.upstream_protocol, is_grpc, client_version);
+        let (mut head, body) = req.into_parts();
+        authorize_upstream_proxy(self.upstream_proxy.as_ref(), &mut head);
+        let (body, client_upgrade) = match upgrade {
+            Some(_) => (Body::empty(), Some(body.on_upgrade())),
+            None => (body, None),
+        };
+        let client = if is_grpc {
+            &self.grpc_client
+        } else {
+            &self.client
+        };
+        let req = Request::from_parts(head, TeeBody::passthrough(body));
+        let sent = send_timed(client, req, self.request_timeout);
+        let fut = sent.then(move |res| {
+            let mut res = match (res, client_upgrade) {
+                (Ok(res), Some(client_upgrade))
+                    if res.status() == StatusCode::SWITCHING_PROTOCOLS =>
+                {
+                    let (head, body) = res.into_parts();
+                    let err_logger = logger.new(o!("area" => "websocket-error"));
+                    let relay = client_upgrade
+                        .join(body.on_upgrade())
+                        .map_err(Error::from)
+                        .and_then(|(client, upstream)| {
+                            relay_bytes(client, upstream).map_err(Error::from)
+                        })
+                        .map_err(move |e| error!(err_logger, "{}", e));
+                    rt::spawn(relay);
+                    Response::from_parts(head, Body::empty())
+                }
+                (Ok(res), _) => create_proxied_response(res),
+                (Err(e), _) => {
+                    error!(logger, "{}", e);
+                    e.response(client_version)
+                }
+            };
+            *res.version_mut() = client_version;
+            Ok(res)
+        });
+        Box::new(fut)
+    }
+}
+
+/// Copy bytes in both directions, closing each side once the other has
+fn relay_bytes(client: Upgraded, upstream: Upgraded) -> impl Future<Item = (), Error = io::Error> {
+    let (client_read, client_write) = client.split();
+    let (upstream_read, upstream_write) = upstream.split();
+    let to_upstream =
+        tokio::io::copy(client_read, upstream_write).and_then(|(_, _, w)| tokio::io::shutdown(w));
+    let to_client =
+        tokio::io::copy(upstream_read, client_write).and_then(|(_, _, w)| tokio::io::shutdown(w));
+    to_upstream.join(to_client).map(|_| ())
+}
+
 pub fn get_proxy_servers<I: IntoIterator<Item = ProxyServerConfig>>(
     logger: Logger,
     servers: I,
@@ -294,24 +1129,242 @@ pub fn get_proxy_servers<I: IntoIterator<Item = ProxyServerConfig>>(
         let req_logger = logger.new(o!( "lifecycle" => "run"));
         let start_logger = logger.new(o!("lifecycle" => "startup"));
         let serve_logger = logger.new(o!("lifecycle" => "error"));
+        let listen_logger = logger.new(o!("lifecycle" => "accept"));
         let socket = s.socket;
-        let factory = MakeProxyService::new(
-            &req_logger,
-            s.proxy_for,
-            s.name,
-            s.archive_path,
-            s.ignored_status_codes,
-        );
+        let protocol = s.protocol;
+        let tls = s.tls.clone();
+        let factory = MakeProxyService::new(&req_logger, s);
         future::lazy(move || {
             info!(start_logger, "Listening on {}", &socket);
             Ok::<(), ()>(())
         })
-        .then(move |_| {
-            Server::bind(&socket)
-                .serve(factory)
-                .map_err(move |e| error!(serve_logger, "{}", e))
+        .then(move |_| match Listener::bind(listen_logger, &socket, tls) {
+            Ok(incoming) => Either::A(
+                protocol
+                    .configure(Server::builder(incoming))
+                    .serve(factory)
+                    .map_err(move |e| error!(serve_logger, "{}", e)),
+            ),
+            Err(e) => {
+                error!(serve_logger, "Couldn't listen on {}: {}", &socket, e);
+                Either::B(future::err(()))
+            }
         })
     });
 
     future::join_all(futs).map(|_| ())
 }
+
+#[cfg(test)]
+mod test {
+    use super::{build_client, send_upstream, Passthrough, UpstreamFailure};
+    use crate::config::{Protocol, ProxyServerConfig};
+    use crate::route::{PathMatcher, Route};
+    use crate::tee::{self, TeeBody};
+    use crate::upstream::UpstreamProxy;
+    use futures::sync::mpsc;
+    use futures::{Future, Stream};
+    use hyper::header;
+    use hyper::service::service_fn_ok;
+    use hyper::{Body, Chunk, Method, Request, Response, Server, StatusCode};
+    use slog::{Discard, Logger};
+    use std::io::{self, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+    use tokio::runtime::current_thread::Runtime;
+
+    fn config(uri: &str) -> ProxyServerConfig {
+        ProxyServerConfig::new(
+            "test",
+            "127.0.0.1:0".parse().unwrap(),
+            uri.parse().unwrap(),
+            "unused",
+            vec![],
+        )
+    }
+
+    /// How sending a GET to `uri` fails
+    fn failure(uri: &str, configure: impl FnOnce(&mut ProxyServerConfig)) -> UpstreamFailure {
+        let mut config = config(uri);
+        configure(&mut config);
+        let client = build_client(Protocol::Auto, &config);
+        let req = Request::get(uri)
+            .body(TeeBody::passthrough(Body::empty()))
+            .unwrap();
+        let sent = Runtime::new().unwrap().block_on(client.request(req));
+        UpstreamFailure::Failed(sent.unwrap_err())
+    }
+
+    /// A port nothing is listening on
+    fn closed_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    /// Accepts one connection and drops it once something is sent on it
+    fn hang_up() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut tcp, _) = listener.accept().unwrap();
+            let _ = tcp.read(&mut [0; 1024]);
+        });
+        port
+    }
+
+    /// Accepts one connection and keeps it open for `hold` without answering
+    fn stall(hold: Duration) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let _tcp = listener.accept().unwrap();
+            thread::sleep(hold);
+        });
+        port
+    }
+
+    #[test]
+    fn test_failure_status() {
+        let refused = failure(&format!("http://127.0.0.1:{}/", closed_port()), |_| {});
+        assert_eq!(StatusCode::BAD_GATEWAY, refused.status());
+
+        let closed = failure(&format!("http://127.0.0.1:{}/", hang_up()), |_| {});
+        assert_eq!(StatusCode::BAD_GATEWAY, closed.status());
+
+        // An upstream proxy that never answers the CONNECT keeps the connection open
+        let proxy = stall(Duration::from_secs(2));
+        let connect_timed_out = failure("https://example.com/", |config| {
+            config.connect_timeout = Some(Duration::from_millis(100));
+            let uri = format!("http://127.0.0.1:{}", proxy);
+            config.upstream_proxy = Some(UpstreamProxy::new(&uri, vec![]).unwrap());
+        });
+        assert_eq!(StatusCode::GATEWAY_TIMEOUT, connect_timed_out.status());
+
+        assert_eq!(
+            StatusCode::GATEWAY_TIMEOUT,
+            UpstreamFailure::TimedOut(100).status()
+        );
+    }
+
+    #[test]
+    fn test_failure_is_retryable() {
+        let refused = failure(&format!("http://127.0.0.1:{}/", closed_port()), |_| {});
+        assert!(refused.is_retryable(&Method::GET));
+        assert!(refused.is_retryable(&Method::POST));
+
+        let closed = failure(&format!("http://127.0.0.1:{}/", hang_up()), |_| {});
+        assert!(closed.is_retryable(&Method::GET));
+        assert!(!closed.is_retryable(&Method::POST));
+
+        let timed_out = UpstreamFailure::TimedOut(100);
+        assert!(timed_out.is_retryable(&Method::PUT));
+        assert!(!timed_out.is_retryable(&Method::POST));
+        assert!(!timed_out.is_retryable(&Method::PATCH));
+    }
+
+    #[test]
+    fn test_first_attempt_streams_upload() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("http://{}/upload", listener.local_addr().unwrap());
+        let (received, first_received) = channel();
+        thread::spawn(move || {
+            let (mut tcp, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut read_until = |end: &str| {
+                while !String::from_utf8_lossy(&request).contains(end) {
+                    let mut data = [0; 1024];
+                    let n = tcp.read(&mut data).unwrap();
+                    request.extend_from_slice(&data[..n]);
+                }
+            };
+            read_until("first");
+            received.send(()).unwrap();
+            read_until("0\r\n\r\n");
+            tcp.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+        });
+
+        let (upload, chunks) = mpsc::unbounded();
+        let body = Body::wrap_stream(chunks.map_err(|()| io::Error::other("upload dropped")));
+        let uploading = thread::spawn(move || {
+            upload.unbounded_send(Chunk::from("first ")).unwrap();
+            // Only gets there if the request went out before the upload ended
+            let streamed = first_received.recv_timeout(Duration::from_secs(5)).is_ok();
+            upload.unbounded_send(Chunk::from("last")).unwrap();
+            streamed
+        });
+
+        let mut config = ProxyServerConfig::new(
+            "test",
+            "127.0.0.1:0".parse().unwrap(),
+            uri.parse().unwrap(),
+            "unused",
+            vec![],
+        );
+        config.retries = 1;
+        let client = build_client(Protocol::Auto, &config);
+        let (head, _) = Request::post(uri).body(()).unwrap().into_parts();
+        let spool = tee::shared_spool(config.spool_threshold);
+        let logger = Logger::root(Discard, o!());
+        let sending = send_upstream(client, head, body, spool.clone(), &config, logger);
+        let response = Runtime::new().unwrap().block_on(sending).unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert!(uploading.join().unwrap());
+        let recorded = spool.lock().unwrap().take().unwrap().into_bytes().unwrap();
+        assert_eq!(b"first last".to_vec(), recorded);
+    }
+
+    #[test]
+    fn test_passthrough_forward() {
+        let mut rt = Runtime::new().unwrap();
+        // Answers with what it was sent
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(|| {
+            service_fn_ok(|req: Request<Body>| {
+                Response::new(Body::from(format!(
+                    "{} {:?} keep-alive: {}",
+                    req.uri(),
+                    req.headers()[header::HOST],
+                    req.headers().contains_key("keep-alive")
+                )))
+            })
+        });
+        let upstream = format!("http://{}", server.local_addr());
+        rt.spawn(server.map_err(|e| panic!("{}", e)));
+        let route = Route::new(
+            "api",
+            PathMatcher::prefix("/api"),
+            upstream.parse().unwrap(),
+        );
+        let passthrough = Passthrough::new(vec![route.unwrap()], &config(&upstream));
+        let logger = Logger::root(Discard, o!());
+
+        let req = Request::get("/api/items?page=2")
+            .header("keep-alive", "timeout=5")
+            .body(Body::empty())
+            .unwrap();
+        let route = passthrough.route("/api/items").unwrap();
+        let res = rt.block_on(passthrough.forward(route, req, logger.clone()));
+        let (parts, body) = res.unwrap().into_parts();
+        let body = rt.block_on(body.concat2()).unwrap();
+        assert_eq!(StatusCode::OK, parts.status);
+        let host = &upstream["http://".len()..];
+        let expected = format!("/api/items?page=2 {:?} keep-alive: false", host);
+        assert_eq!(expected.as_bytes(), &body[..]);
+
+        // The project's timeouts apply
+        let upstream = format!("http://127.0.0.1:{}", stall(Duration::from_secs(2)));
+        let route = Route::new("slow", PathMatcher::prefix("/"), upstream.parse().unwrap());
+        let mut config = config(&upstream);
+        config.request_timeout = Some(Duration::from_millis(100));
+        let passthrough = Passthrough::new(vec![route.unwrap()], &config);
+        let req = Request::get("/slow").body(Body::empty()).unwrap();
+        let route = passthrough.route("/slow").unwrap();
+        let res = rt.block_on(passthrough.forward(route, req, logger));
+        assert_eq!(StatusCode::GATEWAY_TIMEOUT, res.unwrap().status());
+    }
+}
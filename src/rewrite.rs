@@ -0,0 +1,256 @@
+use crate::archive::{ContentEncoding, Recoder};
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::Uri;
+use std::cmp::Reverse;
+
+/// Response headers rewritten when none are configured
+pub const DEFAULT_HEADERS: &[&str] = &["location", "content-location", "link"];
+
+/// Where to point the upstream's absolute URLs back at the listener
+#[derive(Debug, Clone)]
+pub struct OriginRewrite {
+    pub headers: Vec<HeaderName>,
+    /// Rewrite text bodies as well as headers
+    pub bodies: bool,
+    /// `https` when the listener terminates TLS
+    pub scheme: &'static str,
+}
+
+impl OriginRewrite {
+    pub fn new(headers: Vec<HeaderName>, bodies: bool, scheme: &'static str) -> OriginRewrite {
+        OriginRewrite {
+            headers,
+            bodies,
+            scheme,
+        }
+    }
+
+    /// The origin the client reached the listener at, going by its `Host` (or the
+    /// HTTP/2 authority)
+    pub fn listener_origin(&self, headers: &HeaderMap, uri: &Uri) -> Option<String> {
+        let host = match headers.get(header::HOST) {
+            Some(h) => h.to_str().ok()?.to_string(),
+            None => uri.authority_part()?.to_string(),
+        };
+        Some(format!("{}://{}", self.scheme, host))
+    }
+
+    pub fn rewrite_headers(&self, replacer: &Replacer, headers: &mut HeaderMap) {
+        for name in &self.headers {
+            let values: Vec<HeaderValue> = headers
+                .get_all(name)
+                .iter()
+                .map(|v| match replacer.replace_all(v.as_bytes()) {
+                    Some(r) => HeaderValue::from_bytes(&r).unwrap_or_else(|_| v.clone()),
+                    None => v.clone(),
+                })
+                .collect();
+            headers.remove(name);
+            for v in values {
+                headers.append(name.clone(), v);
+            }
+        }
+    }
+
+    /// Whether to rewrite a body of the type in `headers`; only text can have URLs
+    /// in it
+    pub fn rewrites_body(&self, headers: &HeaderMap) -> bool {
+        self.bodies
+            && headers
+                .get(header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| {
+                    let v = v.to_lowercase();
+                    v.starts_with("text/")
+                        || ["json", "xml", "javascript", "x-www-form-urlencoded"]
+                            .iter()
+                            .any(|t| v.contains(t))
+                })
+                .unwrap_or(false)
+    }
+}
+
+/// `scheme://authority` of an absolute URI
+pub fn origin(uri: &Uri) -> Option<String> {
+    Some(format!("{}://{}", uri.scheme_str()?, uri.authority_part()?))
+}
+
+/// Replaces strings in a body as it streams past.  The end of each chunk that
+/// could be the start of a match is held back until the next one arrives.
+#[derive(Debug, Clone)]
+pub struct Replacer {
+    pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    held: Vec<u8>,
+}
+
+impl Replacer {
+    pub fn new(mut pairs: Vec<(String, String)>) -> Replacer {
+        // Longer origins first, so `http://a:8080` isn't taken for `http://a`
+        pairs.sort_by_key(|p| Reverse(p.0.len()));
+        Replacer {
+            pairs: pairs
+                .into_iter()
+                .filter(|(from, to)| !from.is_empty() && from != to)
+                .map(|(from, to)| (from.into_bytes(), to.into_bytes()))
+                .collect(),
+            held: Vec::new(),
+        }
+    }
+
+    /// The rewritten data, if anything in it was replaced
+    pub fn replace_all(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let mut out = Vec::with_capacity(data.len());
+        if self.scan(data, &mut out, true).0 {
+            Some(out)
+        } else {
+            None
+        }
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<u8> {
+        let mut data = std::mem::take(&mut self.held);
+        data.extend_from_slice(chunk);
+        let mut out = Vec::with_capacity(data.len());
+        let end = self.scan(&data, &mut out, false).1;
+        self.held = data[end..].to_vec();
+        out
+    }
+
+    /// Whatever was held back at the end of the body
+    pub fn finish(&mut self) -> Vec<u8> {
+        let data = std::mem::take(&mut self.held);
+        let mut out = Vec::with_capacity(data.len());
+        self.scan(&data, &mut out, true);
+        out
+    }
+
+    /// Copies `data` to `out` with replacements, stopping early (unless `last`) at
+    /// a possible partial match.  Returns whether anything was replaced and how
+    /// much of `data` was consumed.
+    fn scan(&self, data: &[u8], out: &mut Vec<u8>, last: bool) -> (bool, usize) {
+        let mut replaced = false;
+        let mut i = 0;
+        'outer: while i < data.len() {
+            let rest = &data[i..];
+            for (from, to) in &self.pairs {
+                if rest.starts_with(from) {
+                    out.extend_from_slice(to);
+                    i += from.len();
+                    replaced = true;
+                    continue 'outer;
+                }
+            }
+            if !last
+                && self
+                    .pairs
+                    .iter()
+                    .any(|(from, _)| rest.len() < from.len() && from.starts_with(rest))
+            {
+                return (replaced, i);
+            }
+            out.push(data[i]);
+            i += 1;
+        }
+        (replaced, i)
+    }
+}
+
+/// Rewrites a body as it streams past, decoding it and encoding it again if it
+/// has a `Content-Encoding`
+pub struct BodyRewrite {
+    replacer: Replacer,
+    recoder: Option<Recoder>,
+    /// Set once the body failed to decode.  The rest of it is passed on as it is,
+    /// which leaves the client to fail as it would have without the rewrite.
+    broken: bool,
+}
+
+impl BodyRewrite {
+    pub fn new(replacer: Replacer, encoding: Option<ContentEncoding>) -> BodyRewrite {
+        BodyRewrite {
+            replacer,
+            recoder: encoding.map(Recoder::new),
+            broken: false,
+        }
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<u8> {
+        let replacer = &mut self.replacer;
+        match self.recoder {
+            _ if self.broken => chunk.to_vec(),
+            Some(ref mut recoder) => {
+                recoder
+                    .push(chunk, |d| replacer.push(d))
+                    .unwrap_or_else(|_| {
+                        self.broken = true;
+                        chunk.to_vec()
+                    })
+            }
+            None => replacer.push(chunk),
+        }
+    }
+
+    /// Whatever was held back at the end of the body
+    pub fn finish(&mut self) -> Vec<u8> {
+        let replacer = &mut self.replacer;
+        match self.recoder {
+            _ if self.broken => Vec::new(),
+            Some(ref mut recoder) => recoder
+                .finish(|d| {
+                    let mut out = replacer.push(d);
+                    out.extend(replacer.finish());
+                    out
+                })
+                .unwrap_or_default(),
+            None => replacer.finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BodyRewrite, Replacer};
+    use crate::archive::ContentEncoding;
+
+    #[test]
+    fn test_streaming_replace() {
+        let pairs = vec![(
+            "https://api.example.com".to_string(),
+            "http://localhost:8080".to_string(),
+        )];
+        let mut replacer = Replacer::new(pairs);
+        let mut out = replacer.push(br#"{"next": "https://api.exa"#);
+        out.extend(replacer.push(br#"mple.com/page/2", "host": "https://api"#));
+        out.extend(replacer.finish());
+        assert_eq!(
+            &br#"{"next": "http://localhost:8080/page/2", "host": "https://api"#[..],
+            &out[..]
+        );
+        assert_eq!(None, replacer.replace_all(b"/page/2"));
+    }
+
+    #[test]
+    fn test_compressed_body_rewrite() {
+        let body = br#"{"next": "https://api.example.com/page/2"}"#;
+        for enc in &[
+            ContentEncoding::Gzip,
+            ContentEncoding::Deflate,
+            ContentEncoding::Brotli,
+        ] {
+            let pairs = vec![(
+                "https://api.example.com".to_string(),
+                "http://localhost:8080".to_string(),
+            )];
+            let mut rewrite = BodyRewrite::new(Replacer::new(pairs), Some(*enc));
+            let mut out = Vec::new();
+            for chunk in enc.encode(body).unwrap().chunks(5) {
+                out.extend(rewrite.push(chunk));
+            }
+            out.extend(rewrite.finish());
+            assert_eq!(
+                &br#"{"next": "http://localhost:8080/page/2"}"#[..],
+                &enc.decode(&out).unwrap()[..]
+            );
+        }
+    }
+}
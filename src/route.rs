@@ -0,0 +1,123 @@
+use failure::Error;
+use hyper::header::HeaderValue;
+use hyper::Uri;
+use regex::Regex;
+
+#[derive(Debug, Fail)]
+#[fail(display = "Route upstream '{}' has no authority", uri)]
+pub struct RouteUpstreamError {
+    uri: Uri,
+}
+
+/// Which request paths a route applies to
+#[derive(Debug, Clone)]
+pub enum PathMatcher {
+    /// The path itself, or anything below it
+    Prefix(String),
+    /// Matched against the start of the path
+    Regex(Regex),
+}
+
+impl PathMatcher {
+    pub fn prefix<S: Into<String>>(prefix: S) -> PathMatcher {
+        PathMatcher::Prefix(prefix.into())
+    }
+
+    pub fn regex(pattern: &str) -> Result<PathMatcher, Error> {
+        Ok(PathMatcher::Regex(Regex::new(&format!(
+            "^(?:{})",
+            pattern
+        ))?))
+    }
+
+    /// The leading part of `path` that matched
+    pub fn find<'a>(&self, path: &'a str) -> Option<&'a str> {
+        match self {
+            PathMatcher::Prefix(p) => {
+                let rest = path.strip_prefix(p.as_str())?;
+                if rest.is_empty() || rest.starts_with('/') || p.ends_with('/') {
+                    Some(&path[..p.len()])
+                } else {
+                    None
+                }
+            }
+            PathMatcher::Regex(r) => r.find(path).map(|m| m.as_str()),
+        }
+    }
+}
+
+/// Sends the requests whose paths match to an upstream of their own
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub name: String,
+    pub matcher: PathMatcher,
+    pub upstream: Uri,
+    /// Remove the matched part of the path before sending the request upstream
+    pub strip_prefix: bool,
+    host_header: HeaderValue,
+}
+
+impl Route {
+    pub fn new<S: Into<String>>(
+        name: S,
+        matcher: PathMatcher,
+        upstream: Uri,
+    ) -> Result<Route, Error> {
+        let host_header = match upstream.authority_part() {
+            Some(a) => a.as_str().parse()?,
+            None => return Err(RouteUpstreamError { uri: upstream }.into()),
+        };
+        Ok(Route {
+            name: name.into(),
+            matcher,
+            upstream,
+            strip_prefix: false,
+            host_header,
+        })
+    }
+
+    /// `Host` to send to the upstream
+    pub fn host_header(&self) -> &HeaderValue {
+        &self.host_header
+    }
+
+    /// The path to request from the upstream, if the route applies to `path`
+    pub fn upstream_path(&self, path: &str) -> Option<String> {
+        let matched = self.matcher.find(path)?;
+        if !self.strip_prefix {
+            return Some(path.to_string());
+        }
+        let rest = &path[matched.len()..];
+        if rest.starts_with('/') {
+            Some(rest.to_string())
+        } else {
+            Some(format!("/{}", rest))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PathMatcher, Route};
+
+    #[test]
+    fn test_route_paths() {
+        let upstream = "http://auth.example.com".parse().unwrap();
+        let mut route = Route::new("auth", PathMatcher::prefix("/auth"), upstream).unwrap();
+        assert_eq!(
+            Some("/auth/login".to_string()),
+            route.upstream_path("/auth/login")
+        );
+        assert_eq!(None, route.upstream_path("/authors"));
+        route.strip_prefix = true;
+        assert_eq!(
+            Some("/login".to_string()),
+            route.upstream_path("/auth/login")
+        );
+        assert_eq!(Some("/".to_string()), route.upstream_path("/auth"));
+
+        let matcher = PathMatcher::regex("/v[0-9]+").unwrap();
+        assert_eq!(Some("/v2"), matcher.find("/v2/users"));
+        assert_eq!(None, matcher.find("/api/v2"));
+    }
+}
@@ -0,0 +1,124 @@
+use serde::de::{self, Deserialize, Deserializer};
+use serde_derive::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Fail)]
+#[fail(
+    display = "'{}' isn't a status code, class (like 5xx) or range (like 400-499)",
+    _0
+)]
+pub struct StatusRangeError(String);
+
+/// Response status codes, written as a single code (`503`), a class (`5xx`) or an
+/// inclusive range (`400-499`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatusRange {
+    start: u16,
+    end: u16,
+}
+
+impl StatusRange {
+    pub fn contains(self, status: u16) -> bool {
+        self.start <= status && status <= self.end
+    }
+}
+
+impl From<u16> for StatusRange {
+    fn from(code: u16) -> StatusRange {
+        StatusRange {
+            start: code,
+            end: code,
+        }
+    }
+}
+
+impl FromStr for StatusRange {
+    type Err = StatusRangeError;
+
+    fn from_str(s: &str) -> Result<StatusRange, StatusRangeError> {
+        let err = || StatusRangeError(s.to_string());
+        let code = |c: &str| c.trim().parse::<u16>().map_err(|_| err());
+        let s = s.trim();
+        // Checked for ASCII first, so slicing by bytes stays on character boundaries
+        let range = if s.is_ascii() && s.len() == 3 && s[1..].eq_ignore_ascii_case("xx") {
+            let class = code(&s[..1])?;
+            StatusRange {
+                start: class * 100,
+                end: class * 100 + 99,
+            }
+        } else if let Some(i) = s.find('-') {
+            StatusRange {
+                start: code(&s[..i])?,
+                end: code(&s[i + 1..])?,
+            }
+        } else {
+            StatusRange::from(code(s)?)
+        };
+        if range.start < 100 || range.end > 999 || range.start > range.end {
+            return Err(err());
+        }
+        Ok(range)
+    }
+}
+
+impl fmt::Display for StatusRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for StatusRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<StatusRange, D::Error> {
+        // Plain codes can be written as numbers, as they were before ranges
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Code(u16),
+            Pattern(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Code(c) => Ok(StatusRange::from(c)),
+            Raw::Pattern(p) => p.parse().map_err(de::Error::custom),
+        }
+    }
+}
+
+/// Whether a response with `status` is left out of the recordings: when it's one
+/// of the `ignored` codes, or `recorded` lists the only codes to keep and it isn't
+/// one of those
+pub fn is_ignored(status: u16, ignored: &[StatusRange], recorded: Option<&[StatusRange]>) -> bool {
+    ignored.iter().any(|r| r.contains(status))
+        || recorded.is_some_and(|r| !r.iter().any(|r| r.contains(status)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_ignored, StatusRange};
+
+    #[test]
+    fn test_status_ranges() {
+        let class: StatusRange = "5xx".parse().unwrap();
+        assert!(class.contains(503));
+        assert!(!class.contains(499));
+        let range: StatusRange = "400-404".parse().unwrap();
+        assert!(range.contains(404));
+        assert!(!range.contains(405));
+        assert_eq!(StatusRange::from(503), "503".parse().unwrap());
+        assert!("6x".parse::<StatusRange>().is_err());
+        assert!("éx".parse::<StatusRange>().is_err());
+        assert!("5é".parse::<StatusRange>().is_err());
+        assert!("499-400".parse::<StatusRange>().is_err());
+
+        let ignored = vec![StatusRange::from(404)];
+        let recorded = vec!["2xx".parse().unwrap(), StatusRange::from(404)];
+        assert!(is_ignored(404, &ignored, Some(&recorded)));
+        assert!(is_ignored(500, &ignored, Some(&recorded)));
+        assert!(!is_ignored(201, &ignored, Some(&recorded)));
+        assert!(!is_ignored(500, &ignored, None));
+    }
+}
@@ -0,0 +1,394 @@
+use crate::archive::SpooledBody;
+use crate::rewrite::BodyRewrite;
+use crate::trailers::TrailerSlot;
+use futures::sync::oneshot;
+use futures::{try_ready, Async, Poll, Stream};
+use hyper::body::Payload;
+use hyper::header::HeaderMap;
+use hyper::{Body, Chunk};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Bodies larger than this are spooled to a temp file while being recorded
+pub const DEFAULT_SPOOL_THRESHOLD: usize = 1024 * 1024;
+
+/// How much of a spooled file is sent at a time when it's replayed
+const REPLAY_PIECE: usize = 64 * 1024;
+
+/// Buffer for a body being recorded, kept in memory until it grows past
+/// `threshold` bytes and moved to an anonymous temp file after that.
+/// The size and arrival time (relative to creation) of each write is kept as well,
+/// along with any trailers that followed the body.
+pub struct Spool {
+    threshold: usize,
+    memory: Vec<u8>,
+    file: Option<File>,
+    error: Option<io::Error>,
+    started: Instant,
+    chunks: Vec<(Duration, usize)>,
+    trailers: Option<HeaderMap>,
+    /// When the body ended, relative to creation
+    ended: Option<Duration>,
+}
+
+impl Spool {
+    pub fn new(threshold: usize) -> Spool {
+        Spool {
+            threshold,
+            memory: Vec::new(),
+            file: None,
+            error: None,
+            started: Instant::now(),
+            chunks: Vec::new(),
+            trailers: None,
+            ended: None,
+        }
+    }
+
+    pub fn write(&mut self, data: &[u8]) {
+        self.chunks.push((self.started.elapsed(), data.len()));
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = self.try_write(data) {
+            self.error = Some(e);
+        }
+    }
+
+    fn try_write(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.file.is_none() && self.memory.len() + data.len() > self.threshold {
+            let mut file = tempfile::tempfile()?;
+            file.write_all(&self.memory)?;
+            self.memory = Vec::new();
+            self.file = Some(file);
+        }
+        match self.file {
+            Some(ref mut f) => f.write_all(data),
+            None => {
+                self.memory.extend_from_slice(data);
+                Ok(())
+            }
+        }
+    }
+
+    /// A body to send everything written so far again, read from the temp file a
+    /// piece at a time if it was spooled there.  Nothing should be written while
+    /// it's being read.
+    pub fn replay(&self) -> io::Result<Body> {
+        if let Some(ref e) = self.error {
+            return Err(io::Error::new(e.kind(), e.to_string()));
+        }
+        let replay = Replay {
+            memory: Some(Chunk::from(self.memory.clone())),
+            file: match self.file {
+                Some(ref f) => Some(f.try_clone()?),
+                None => None,
+            },
+            offset: 0,
+        };
+        Ok(Body::wrap_stream(replay))
+    }
+
+    /// Takes everything written so far, leaving the spool empty.
+    pub fn take(&mut self) -> io::Result<SpooledBody> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        match self.file.take() {
+            Some(f) => Ok(SpooledBody::File(f)),
+            None => Ok(SpooledBody::Memory(mem::take(&mut self.memory))),
+        }
+    }
+
+    pub fn take_chunks(&mut self) -> Vec<(Duration, usize)> {
+        mem::take(&mut self.chunks)
+    }
+
+    pub fn take_trailers(&mut self) -> Option<HeaderMap> {
+        self.trailers.take()
+    }
+
+    /// Mark the end of the body, which `trailers` may have followed
+    pub fn end(&mut self, trailers: Option<HeaderMap>) {
+        self.ended = Some(self.started.elapsed());
+        self.trailers = trailers;
+    }
+
+    /// How long after the spool was created the body ended, if it has
+    pub fn ended(&self) -> Option<Duration> {
+        self.ended
+    }
+}
+
+pub type SharedSpool = Arc<Mutex<Spool>>;
+
+pub fn shared_spool(threshold: usize) -> SharedSpool {
+    Arc::new(Mutex::new(Spool::new(threshold)))
+}
+
+/// What `Spool::replay` reads back.  The file shares its position with the
+/// spool's, so it's sought before each read.
+struct Replay {
+    memory: Option<Chunk>,
+    file: Option<File>,
+    offset: u64,
+}
+
+impl Stream for Replay {
+    type Item = Chunk;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, io::Error> {
+        if let Some(chunk) = self.memory.take() {
+            if !chunk.is_empty() {
+                return Ok(Async::Ready(Some(chunk)));
+            }
+        }
+        let file = match self.file {
+            Some(ref mut f) => f,
+            None => return Ok(Async::Ready(None)),
+        };
+        let mut buf = vec![0; REPLAY_PIECE];
+        file.seek(SeekFrom::Start(self.offset))?;
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(Async::Ready(None));
+        }
+        buf.truncate(n);
+        self.offset += n as u64;
+        Ok(Async::Ready(Some(Chunk::from(buf))))
+    }
+}
+
+/// A body that forwards chunks as they arrive while copying them into a
+/// `Spool`. `on_complete` runs once the inner body has been fully read.
+///
+/// Trailers are read as soon as the data ends, rather than when they're asked
+/// for, so they're recorded even if the connection to the client (HTTP/1) never
+/// asks for them.
+pub struct TeeBody {
+    inner: Body,
+    spool: Option<SharedSpool>,
+    on_complete: Option<Box<dyn FnOnce() + Send>>,
+    data_done: bool,
+    trailers: Option<HeaderMap>,
+    /// Where an HTTP/1 upstream connection leaves the trailers it read
+    upstream: Option<TrailerSlot>,
+    /// Where to leave the trailers for an HTTP/1 client connection to write
+    downstream: Option<TrailerSlot>,
+    /// Applied to what's forwarded, but not to what's recorded
+    rewrite: Option<BodyRewrite>,
+    /// Gets whatever of the inner body is left unread when this is dropped
+    leftover: Option<oneshot::Sender<Option<Body>>>,
+}
+
+impl TeeBody {
+    pub fn new<F>(inner: Body, spool: SharedSpool, on_complete: F) -> TeeBody
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        TeeBody {
+            inner,
+            spool: Some(spool),
+            on_complete: Some(Box::new(on_complete)),
+            data_done: false,
+            trailers: None,
+            upstream: None,
+            downstream: None,
+            rewrite: None,
+            leftover: None,
+        }
+    }
+
+    /// Tee into `spool` without anything to run on completion
+    pub fn spooled(inner: Body, spool: SharedSpool) -> TeeBody {
+        TeeBody {
+            inner,
+            spool: Some(spool),
+            on_complete: None,
+            data_done: false,
+            trailers: None,
+            upstream: None,
+            downstream: None,
+            rewrite: None,
+            leftover: None,
+        }
+    }
+
+    /// Forward the body without recording it
+    pub fn passthrough(inner: Body) -> TeeBody {
+        TeeBody {
+            inner,
+            spool: None,
+            on_complete: None,
+            data_done: false,
+            trailers: None,
+            upstream: None,
+            downstream: None,
+            rewrite: None,
+            leftover: None,
+        }
+    }
+
+    /// Pass trailers between HTTP/1 connections, which hyper can't do itself
+    pub fn trailer_slots(
+        mut self,
+        upstream: Option<TrailerSlot>,
+        downstream: Option<TrailerSlot>,
+    ) -> TeeBody {
+        self.upstream = upstream;
+        self.downstream = downstream;
+        self
+    }
+
+    /// Rewrite the forwarded body
+    pub fn rewrite(mut self, rewrite: Option<BodyRewrite>) -> TeeBody {
+        self.rewrite = rewrite;
+        self
+    }
+
+    /// Hand what's left of the inner body to `sender` once this is dropped, or
+    /// `None` if it was read to the end, so an upload that failed part way through
+    /// can still be read
+    pub fn leftover(mut self, sender: oneshot::Sender<Option<Body>>) -> TeeBody {
+        self.leftover = Some(sender);
+        self
+    }
+
+    fn complete(&mut self) {
+        if let Some(f) = self.on_complete.take() {
+            f();
+        }
+    }
+}
+
+impl Payload for TeeBody {
+    type Data = Chunk;
+    type Error = hyper::Error;
+
+    fn poll_data(&mut self) -> Poll<Option<Self::Data>, Self::Error> {
+        if self.data_done {
+            return Ok(Async::Ready(None));
+        }
+        loop {
+            let chunk = match try_ready!(self.inner.poll_data()) {
+                Some(chunk) => chunk,
+                None => break,
+            };
+            if let Some(ref spool) = self.spool {
+                spool.lock().unwrap().write(&chunk);
+            }
+            match self.rewrite {
+                Some(ref mut r) => {
+                    let rewritten = r.push(&chunk);
+                    // Everything may have been held back for the next chunk
+                    if !rewritten.is_empty() {
+                        return Ok(Async::Ready(Some(Chunk::from(rewritten))));
+                    }
+                }
+                None => return Ok(Async::Ready(Some(chunk))),
+            }
+        }
+        let trailers = match try_ready!(self.inner.poll_trailers()) {
+            Some(t) => Some(t),
+            None => self.upstream.as_ref().and_then(TrailerSlot::take),
+        };
+        if let Some(ref spool) = self.spool {
+            spool.lock().unwrap().end(trailers.clone());
+        }
+        if let (Some(ref slot), Some(ref t)) = (&self.downstream, &trailers) {
+            slot.put(t.clone());
+        }
+        self.trailers = trailers;
+        self.data_done = true;
+        self.complete();
+        match self.rewrite.as_mut().map(BodyRewrite::finish) {
+            Some(rest) if !rest.is_empty() => Ok(Async::Ready(Some(Chunk::from(rest)))),
+            _ => Ok(Async::Ready(None)),
+        }
+    }
+
+    fn poll_trailers(&mut self) -> Poll<Option<HeaderMap>, Self::Error> {
+        if self.data_done {
+            Ok(Async::Ready(self.trailers.take()))
+        } else {
+            self.inner.poll_trailers()
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        if self.data_done {
+            self.trailers.is_none()
+        } else {
+            self.inner.is_end_stream()
+        }
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        match self.rewrite {
+            Some(_) => None,
+            None => self.inner.content_length(),
+        }
+    }
+}
+
+impl Drop for TeeBody {
+    fn drop(&mut self) {
+        // Empty bodies (and responses to HEAD) may never be polled, and one with a
+        // length isn't polled again once it's all been read
+        if !self.data_done && self.inner.is_end_stream() {
+            if let Some(ref spool) = self.spool {
+                spool.lock().unwrap().end(None);
+            }
+        }
+        let done = self.data_done || self.inner.is_end_stream();
+        if done {
+            self.complete();
+        }
+        if let Some(sender) = self.leftover.take() {
+            let rest = if done {
+                None
+            } else {
+                Some(mem::replace(&mut self.inner, Body::empty()))
+            };
+            let _ = sender.send(rest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Spool;
+    use futures::{Future, Stream};
+
+    #[test]
+    fn test_spool_overflows_to_file() {
+        let mut spool = Spool::new(4);
+        spool.write(b"abc");
+        assert!(spool.file.is_none());
+        spool.write(b"defg");
+        assert!(spool.file.is_some());
+        let body = spool.take().unwrap().into_bytes().unwrap();
+        assert_eq!(b"abcdefg".to_vec(), body);
+        let sizes: Vec<usize> = spool.take_chunks().into_iter().map(|(_, s)| s).collect();
+        assert_eq!(vec![3, 4], sizes);
+    }
+
+    #[test]
+    fn test_spool_replay() {
+        for &threshold in &[1024, 4] {
+            let mut spool = Spool::new(threshold);
+            spool.write(b"abc");
+            spool.write(b"defg");
+            for _ in 0..2 {
+                let body = spool.replay().unwrap().concat2().wait().unwrap();
+                assert_eq!(b"abcdefg", &body[..]);
+            }
+            let body = spool.take().unwrap().into_bytes().unwrap();
+            assert_eq!(b"abcdefg".to_vec(), body);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use chrono::Utc;
+use hyper::header::HeaderMap;
+use hyper::http::request::Parts as RequestParts;
+use serde_json::Value;
+use std::fmt::Write;
+
+/// The request a templated response is answering
+pub struct TemplateContext<'a> {
+    method: &'a str,
+    path: &'a str,
+    query: Vec<(String, String)>,
+    headers: &'a HeaderMap,
+    body: &'a [u8],
+    json: Option<Value>,
+    /// Captured by the path pattern of the recording
+    params: Vec<(String, String)>,
+}
+
+impl<'a> TemplateContext<'a> {
+    pub fn new(req: &'a RequestParts, body: &'a [u8]) -> TemplateContext<'a> {
+        let query = req
+            .uri
+            .query()
+            .map(|q| {
+                url::form_urlencoded::parse(q.as_bytes())
+                    .into_owned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        TemplateContext {
+            method: req.method.as_str(),
+            path: req.uri.path(),
+            query,
+            headers: &req.headers,
+            body,
+            json: serde_json::from_slice(body).ok(),
+            params: Vec::new(),
+        }
+    }
+
+    pub fn params(mut self, params: Vec<(String, String)>) -> TemplateContext<'a> {
+        self.params = params;
+        self
+    }
+
+    /// The value of a `request.` field, empty when the request doesn't have it, or
+    /// `None` if there's no such field
+    fn request(&self, field: &str) -> Option<String> {
+        let (name, rest) = match field.find('.') {
+            Some(i) => (&field[..i], Some(&field[i + 1..])),
+            None => (field, None),
+        };
+        let value = match (name, rest) {
+            ("method", None) => Some(self.method.to_string()),
+            ("path", None) => Some(self.path.to_string()),
+            ("path", Some(n)) => {
+                let n: usize = n.parse().ok()?;
+                self.path
+                    .split('/')
+                    .filter(|s| !s.is_empty())
+                    .nth(n)
+                    .map(str::to_string)
+            }
+            ("query", Some(key)) => self
+                .query
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.clone()),
+            ("params", Some(key)) => self
+                .params
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.clone()),
+            ("headers", Some(key)) => self
+                .headers
+                .get(key)
+                .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned()),
+            ("body", None) => Some(String::from_utf8_lossy(self.body).into_owned()),
+            ("body", Some(pointer)) => {
+                let mut value = self.json.as_ref();
+                for key in pointer.split('.') {
+                    value = value.and_then(|v| match v {
+                        Value::Array(a) => key.parse::<usize>().ok().and_then(|i| a.get(i)),
+                        _ => v.get(key),
+                    });
+                }
+                value.map(|v| match v {
+                    Value::String(s) => s.clone(),
+                    v => v.to_string(),
+                })
+            }
+            _ => return None,
+        };
+        Some(value.unwrap_or_default())
+    }
+
+    /// What a `{{ ... }}` expression renders to, or `None` if it isn't one of ours
+    fn evaluate(&self, expr: &str) -> Option<String> {
+        let (name, arg) = match expr.find(char::is_whitespace) {
+            Some(i) => (&expr[..i], Some(expr[i..].trim())),
+            None => (expr, None),
+        };
+        match (name, arg) {
+            ("now", None) => Some(Utc::now().to_rfc3339()),
+            ("now", Some(format)) => {
+                let mut now = String::new();
+                write!(now, "{}", Utc::now().format(quoted(format)?)).ok()?;
+                Some(now)
+            }
+            ("uuid", None) => Some(uuid()),
+            (field, None) => self.request(field.strip_prefix("request.")?),
+            _ => None,
+        }
+    }
+
+    /// Replace every `{{ ... }}` in `text`.  Anything else between braces, like the
+    /// templates of a client-side framework, is left as it is, as is a `{{` without
+    /// a closing `}}`
+    pub fn render(&self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find("{{") {
+            let end = match rest[start + 2..].find("}}") {
+                Some(end) => start + 2 + end,
+                None => break,
+            };
+            out.push_str(&rest[..start]);
+            match self.evaluate(rest[start + 2..end].trim()) {
+                Some(value) => out.push_str(&value),
+                None => out.push_str(&rest[start..end + 2]),
+            }
+            rest = &rest[end + 2..];
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+fn quoted(arg: &str) -> Option<&str> {
+    arg.strip_prefix('"')?.strip_suffix('"')
+}
+
+/// A random (version 4) UUID
+fn uuid() -> String {
+    let mut b: [u8; 16] = rand::random();
+    b[6] = (b[6] & 0x0f) | 0x40;
+    b[8] = (b[8] & 0x3f) | 0x80;
+    let hex: String = b.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::TemplateContext;
+    use hyper::Request;
+
+    #[test]
+    fn test_render() {
+        let (parts, _) = Request::post("/users/42/orders?sort=date&tag=a%20b")
+            .header("X-Correlation-Id", "abc")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let body = br#"{"order": {"items": [{"sku": "X1"}], "total": 12.5}}"#;
+        let ctx =
+            TemplateContext::new(&parts, body).params(vec![("id".to_string(), "42".to_string())]);
+        assert_eq!(
+            "POST 42 orders 42 date a b abc X1 12.5 []",
+            ctx.render(
+                "{{ request.method }} {{request.path.1}} {{ request.path.2 }} {{ request.params.id }} \
+                 {{ request.query.sort }} {{ request.query.tag }} \
+                 {{ request.headers.x-correlation-id }} {{ request.body.order.items.0.sku }} \
+                 {{ request.body.order.total }} [{{ request.query.missing }}]"
+            )
+        );
+        assert_eq!("{{ unclosed", ctx.render("{{ unclosed"));
+        let id = ctx.render("{{ uuid }}");
+        assert_eq!((36, Some('4')), (id.len(), id.chars().nth(14)));
+        assert_eq!(4, ctx.render("{{ now \"%Y\" }}").len());
+        for other in &[
+            "{{ request.cookies.a }}",
+            "{{ now \"%Q\" }}",
+            "{{ now %Y }}",
+            "<li>{{ item.name | uppercase }}</li>",
+            "{{#each items}}{{this}}{{/each}}",
+        ] {
+            assert_eq!(*other, ctx.render(other));
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use crate::config::Protocol;
+use failure::Error;
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{
+    Certificate, ClientConfig, NoClientAuth, PrivateKey, RootCertStore, ServerCertVerified,
+    ServerCertVerifier, ServerConfig, TLSError,
+};
+use std::fs::{self, File};
+use std::io::{BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio_rustls::webpki::DNSNameRef;
+
+#[derive(Debug, Fail)]
+pub enum TlsError {
+    #[fail(display = "No certificates found in {}", _0)]
+    NoCertificates(String),
+    #[fail(display = "No private key found in {}", _0)]
+    NoPrivateKey(String),
+    #[fail(display = "TLS needs a cert and key, or hostnames for a self-signed cert")]
+    MissingCertificate,
+    #[fail(display = "A client certificate needs a client key")]
+    MissingClientKey,
+    #[fail(display = "A client key needs a client certificate")]
+    MissingClientCert,
+}
+
+/// Write a self-signed certificate for `hostnames` and its key as PEM files
+pub fn write_self_signed(hostnames: &[String], cert: &Path, key: &Path) -> Result<(), Error> {
+    let generated = rcgen::generate_simple_self_signed(hostnames.to_vec());
+    for p in &[cert, key] {
+        if let Some(dir) = p.parent() {
+            fs::create_dir_all(dir)?;
+        }
+    }
+    fs::write(cert, generated.serialize_pem())?;
+    fs::write(key, generated.serialize_private_key_pem())?;
+    Ok(())
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, Error> {
+    certs(&mut BufReader::new(File::open(path)?))
+        .ok()
+        .filter(|c| !c.is_empty())
+        .ok_or_else(|| TlsError::NoCertificates(path.display().to_string()).into())
+}
+
+/// The first PKCS#8 or RSA private key in a PEM file
+fn load_key(path: &Path) -> Result<PrivateKey, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut keys = pkcs8_private_keys(&mut reader).unwrap_or_default();
+    if keys.is_empty() {
+        reader.seek(SeekFrom::Start(0))?;
+        keys = rsa_private_keys(&mut reader).unwrap_or_default();
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| TlsError::NoPrivateKey(path.display().to_string()).into())
+}
+
+/// Server settings for a PEM certificate chain and private key, offering the
+/// HTTP versions of `protocol` through ALPN
+pub fn server_config(cert: &Path, key: &Path, protocol: Protocol) -> Result<ServerConfig, Error> {
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(load_certs(cert)?, load_key(key)?)?;
+    config.set_protocols(&protocol.alpn());
+    Ok(config)
+}
+
+/// Settings for connecting to an upstream, trusting the webpki roots
+pub fn default_client_config() -> ClientConfig {
+    let mut config = ClientConfig::new();
+    config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    config
+}
+
+/// Accepts any certificate the upstream presents
+struct NoVerification;
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _presented_certs: &[Certificate],
+        _dns_name: DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Settings for connecting to an upstream that also trust the CA certificates in
+/// `ca_certs`, present a client certificate if one is given, and skip
+/// verification altogether if `insecure` is set
+pub fn client_config(
+    ca_certs: &[PathBuf],
+    client_cert: Option<(&Path, &Path)>,
+    insecure: bool,
+) -> Result<ClientConfig, Error> {
+    let mut config = default_client_config();
+    for path in ca_certs {
+        let mut reader = BufReader::new(File::open(path)?);
+        match config.root_store.add_pem_file(&mut reader) {
+            Ok((added, _)) if added > 0 => {}
+            _ => return Err(TlsError::NoCertificates(path.display().to_string()).into()),
+        }
+    }
+    if let Some((cert, key)) = client_cert {
+        config.set_single_client_cert(load_certs(cert)?, load_key(key)?);
+    }
+    if insecure {
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NoVerification));
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{client_config, server_config, write_self_signed};
+    use crate::config::Protocol;
+
+    #[test]
+    fn test_self_signed() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = dir.path().join("certs").join("test.crt");
+        let key = dir.path().join("certs").join("test.key");
+        write_self_signed(&["localhost".to_string()], &cert, &key).unwrap();
+        let config = server_config(&cert, &key, Protocol::Auto).unwrap();
+        assert_eq!(
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            config.alpn_protocols
+        );
+        assert!(server_config(&key, &key, Protocol::Auto).is_err());
+    }
+
+    #[test]
+    fn test_client_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = dir.path().join("test.crt");
+        let key = dir.path().join("test.key");
+        write_self_signed(&["localhost".to_string()], &cert, &key).unwrap();
+        let cas = vec![cert.clone()];
+        let config = client_config(&cas, Some((&cert, &key)), false).unwrap();
+        assert!(config.client_auth_cert_resolver.has_certs());
+        assert!(client_config(std::slice::from_ref(&key), None, false).is_err());
+    }
+}
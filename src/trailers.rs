@@ -0,0 +1,744 @@
+use futures::future::{self, Future};
+use futures::{Async, Poll};
+use hyper::client::connect::{Connect, Connected, Destination};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Longest message head or chunk line that will be followed before giving up
+const MAX_LINE: usize = 64 * 1024;
+const MAX_HEADERS: usize = 100;
+
+#[derive(Debug, Default)]
+struct Slot {
+    trailers: Option<HeaderMap>,
+    malformed: bool,
+}
+
+/// Trailers of the response currently on an HTTP/1 connection
+#[derive(Debug, Clone, Default)]
+pub struct TrailerSlot(Arc<Mutex<Slot>>);
+
+impl TrailerSlot {
+    pub fn put(&self, trailers: HeaderMap) {
+        self.0.lock().unwrap().trailers = Some(trailers);
+    }
+
+    pub fn take(&self) -> Option<HeaderMap> {
+        self.0.lock().unwrap().trailers.take()
+    }
+
+    /// Corrupt the chunked encoding of the next response written
+    pub fn malform(&self) {
+        self.0.lock().unwrap().malformed = true;
+    }
+
+    fn take_malformed(&self) -> bool {
+        mem::replace(&mut self.0.lock().unwrap().malformed, false)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Request,
+    Response,
+}
+
+/// What to do with the trailer section of each chunked message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Observe,
+    /// Remove the trailers and put them in the slot
+    Capture,
+    /// Add the trailers from the slot
+    Inject,
+}
+
+#[derive(Debug)]
+enum State {
+    Head(Vec<u8>),
+    Length(u64),
+    /// A chunk size line that isn't hex, written before the first real one
+    MalformedChunkSize,
+    ChunkSize(Vec<u8>),
+    /// Remaining chunk data, plus the CRLF after it
+    ChunkData(u64),
+    Trailers(Vec<u8>),
+    Passthrough,
+}
+
+/// Follows one direction of an HTTP/1 connection
+#[derive(Debug)]
+struct Scanner {
+    kind: Kind,
+    action: Action,
+    state: State,
+}
+
+enum Head {
+    Partial,
+    Complete(usize, State),
+    Invalid,
+}
+
+fn is_chunked(headers: &[httparse::Header]) -> bool {
+    headers
+        .iter()
+        .filter(|h| h.name.eq_ignore_ascii_case("transfer-encoding"))
+        .filter_map(|h| std::str::from_utf8(h.value).ok())
+        .flat_map(|v| v.split(','))
+        .last()
+        .map(|c| c.trim().eq_ignore_ascii_case("chunked"))
+        .unwrap_or(false)
+}
+
+fn content_length(headers: &[httparse::Header]) -> Option<u64> {
+    headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("content-length"))
+        .and_then(|h| std::str::from_utf8(h.value).ok())
+        .and_then(|v| v.trim().parse().ok())
+}
+
+/// State for the body that follows a head, given its headers
+fn body_state(headers: &[httparse::Header], until_close: bool) -> State {
+    if is_chunked(headers) {
+        return State::ChunkSize(Vec::new());
+    }
+    match content_length(headers) {
+        Some(0) => State::Head(Vec::new()),
+        Some(n) => State::Length(n),
+        None if until_close => State::Passthrough,
+        None => State::Head(Vec::new()),
+    }
+}
+
+fn parse_trailers(section: &[u8]) -> HeaderMap {
+    let mut map = HeaderMap::new();
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    if let Ok(httparse::Status::Complete((_, headers))) =
+        httparse::parse_headers(section, &mut headers)
+    {
+        for h in headers.iter() {
+            if let (Ok(k), Ok(v)) = (
+                HeaderName::from_bytes(h.name.as_bytes()),
+                HeaderValue::from_bytes(h.value),
+            ) {
+                map.append(k, v);
+            }
+        }
+    }
+    map
+}
+
+impl Scanner {
+    fn new(kind: Kind, action: Action) -> Scanner {
+        Scanner {
+            kind,
+            action,
+            state: State::Head(Vec::new()),
+        }
+    }
+
+    /// `heads` tracks, for each request seen, whether it was a `HEAD` request and so
+    /// gets a response without a body
+    fn parse_head(&self, buf: &[u8], heads: &mut VecDeque<bool>, slot: &TrailerSlot) -> Head {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        match self.kind {
+            Kind::Request => {
+                let mut req = httparse::Request::new(&mut headers);
+                match req.parse(buf) {
+                    Ok(httparse::Status::Complete(len)) => {
+                        heads.push_back(req.method == Some("HEAD"));
+                        Head::Complete(len, body_state(req.headers, false))
+                    }
+                    Ok(httparse::Status::Partial) => Head::Partial,
+                    Err(_) => Head::Invalid,
+                }
+            }
+            Kind::Response => {
+                let mut res = httparse::Response::new(&mut headers);
+                match res.parse(buf) {
+                    Ok(httparse::Status::Complete(len)) => {
+                        let state = match res.code.unwrap_or(0) {
+                            101 => State::Passthrough,
+                            100..=199 => State::Head(Vec::new()),
+                            code => {
+                                let head_request = heads.pop_front().unwrap_or(false);
+                                if self.action == Action::Capture {
+                                    slot.take();
+                                }
+                                let malformed =
+                                    self.action == Action::Inject && slot.take_malformed();
+                                if head_request || code == 204 || code == 304 {
+                                    State::Head(Vec::new())
+                                } else {
+                                    match body_state(res.headers, true) {
+                                        State::ChunkSize(_) if malformed => {
+                                            State::MalformedChunkSize
+                                        }
+                                        state => state,
+                                    }
+                                }
+                            }
+                        };
+                        Head::Complete(len, state)
+                    }
+                    Ok(httparse::Status::Partial) => Head::Partial,
+                    Err(_) => Head::Invalid,
+                }
+            }
+        }
+    }
+
+    /// Whether the connection is part way through a message, so closing it now would
+    /// cut the message short
+    fn in_message(&self) -> bool {
+        match self.state {
+            State::Head(ref buf) => !buf.is_empty(),
+            State::Passthrough => false,
+            _ => true,
+        }
+    }
+
+    fn end_of_trailers(&self, section: Vec<u8>, out: &mut Vec<u8>, slot: &TrailerSlot) {
+        match self.action {
+            Action::Observe => out.extend_from_slice(&section),
+            Action::Capture => {
+                let trailers = parse_trailers(&section);
+                if !trailers.is_empty() {
+                    slot.put(trailers);
+                }
+                out.extend_from_slice(b"\r\n");
+            }
+            Action::Inject => {
+                out.extend_from_slice(&section[..section.len() - 2]);
+                for (k, v) in slot.take().iter().flat_map(|t| t.iter()) {
+                    out.extend_from_slice(k.as_str().as_bytes());
+                    out.extend_from_slice(b": ");
+                    out.extend_from_slice(v.as_bytes());
+                    out.extend_from_slice(b"\r\n");
+                }
+                out.extend_from_slice(b"\r\n");
+            }
+        }
+    }
+
+    fn process(
+        &mut self,
+        mut input: &[u8],
+        out: &mut Vec<u8>,
+        heads: &mut VecDeque<bool>,
+        slot: &TrailerSlot,
+    ) {
+        while !input.is_empty() {
+            self.state = match mem::replace(&mut self.state, State::Passthrough) {
+                State::Passthrough => {
+                    out.extend_from_slice(input);
+                    input = &[];
+                    State::Passthrough
+                }
+                State::Length(n) => {
+                    let len = n.min(input.len() as u64);
+                    out.extend_from_slice(&input[..len as usize]);
+                    input = &input[len as usize..];
+                    if len == n {
+                        State::Head(Vec::new())
+                    } else {
+                        State::Length(n - len)
+                    }
+                }
+                State::MalformedChunkSize => {
+                    out.extend_from_slice(b"zz\r\n");
+                    State::ChunkSize(Vec::new())
+                }
+                State::ChunkData(n) => {
+                    let len = n.min(input.len() as u64);
+                    out.extend_from_slice(&input[..len as usize]);
+                    input = &input[len as usize..];
+                    if len == n {
+                        State::ChunkSize(Vec::new())
+                    } else {
+                        State::ChunkData(n - len)
+                    }
+                }
+                State::Head(mut buf) => {
+                    let start = buf.len();
+                    buf.extend_from_slice(input);
+                    match self.parse_head(&buf, heads, slot) {
+                        Head::Complete(len, next) => {
+                            out.extend_from_slice(&input[..len - start]);
+                            input = &input[len - start..];
+                            next
+                        }
+                        Head::Partial if buf.len() <= MAX_LINE => {
+                            out.extend_from_slice(input);
+                            input = &[];
+                            State::Head(buf)
+                        }
+                        _ => State::Passthrough,
+                    }
+                }
+                State::ChunkSize(mut line) => match input.iter().position(|b| *b == b'\n') {
+                    Some(i) => {
+                        line.extend_from_slice(&input[..=i]);
+                        out.extend_from_slice(&input[..=i]);
+                        input = &input[i + 1..];
+                        let size = std::str::from_utf8(&line)
+                            .ok()
+                            .and_then(|l| l.split(';').next())
+                            .and_then(|s| u64::from_str_radix(s.trim(), 16).ok());
+                        match size {
+                            Some(0) => State::Trailers(Vec::new()),
+                            Some(n) => State::ChunkData(n + 2),
+                            None => State::Passthrough,
+                        }
+                    }
+                    None if line.len() + input.len() <= MAX_LINE => {
+                        line.extend_from_slice(input);
+                        out.extend_from_slice(input);
+                        input = &[];
+                        State::ChunkSize(line)
+                    }
+                    None => State::Passthrough,
+                },
+                State::Trailers(mut section) => {
+                    let mut next = None;
+                    while let Some((b, rest)) = input.split_first() {
+                        section.push(*b);
+                        input = rest;
+                        if section == b"\r\n" || section.ends_with(b"\r\n\r\n") {
+                            next = Some(State::Head(Vec::new()));
+                            break;
+                        }
+                    }
+                    match next {
+                        Some(next) => {
+                            self.end_of_trailers(section, out, slot);
+                            next
+                        }
+                        None if section.len() <= MAX_LINE => State::Trailers(section),
+                        None => {
+                            out.extend_from_slice(&section);
+                            State::Passthrough
+                        }
+                    }
+                }
+            };
+        }
+    }
+}
+
+/// A connection that follows the HTTP/1 messages read and written on it.
+///
+/// hyper only reads and writes trailers over HTTP/2; on HTTP/1 it fails on a chunked
+/// body that has them and never sends any.  Sitting between hyper and the socket,
+/// this takes trailers out of responses before hyper reads them, and adds them to
+/// responses after hyper writes them.  Connections that turn out not to be HTTP/1
+/// (HTTP/2, upgraded or unparseable ones) are passed through untouched.
+pub struct TrailerIo<T> {
+    inner: T,
+    reading: Scanner,
+    writing: Scanner,
+    heads: VecDeque<bool>,
+    slot: TrailerSlot,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    write_buf: Vec<u8>,
+}
+
+impl<T> TrailerIo<T> {
+    fn new(inner: T, reading: Scanner, writing: Scanner) -> TrailerIo<T> {
+        TrailerIo {
+            inner,
+            reading,
+            writing,
+            heads: VecDeque::new(),
+            slot: TrailerSlot::default(),
+            read_buf: Vec::new(),
+            read_pos: 0,
+            write_buf: Vec::new(),
+        }
+    }
+
+    /// A connection to an upstream, whose response trailers are read into `slot()`
+    pub fn client(inner: T) -> TrailerIo<T> {
+        TrailerIo::new(
+            inner,
+            Scanner::new(Kind::Response, Action::Capture),
+            Scanner::new(Kind::Request, Action::Observe),
+        )
+    }
+
+    /// A connection from a client, with trailers put in `slot()` written after the
+    /// body of the response being sent
+    pub fn server(inner: T) -> TrailerIo<T> {
+        TrailerIo::new(
+            inner,
+            Scanner::new(Kind::Request, Action::Observe),
+            Scanner::new(Kind::Response, Action::Inject),
+        )
+    }
+
+    pub fn slot(&self) -> TrailerSlot {
+        self.slot.clone()
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: Write> TrailerIo<T> {
+    fn drain(&mut self) -> io::Result<()> {
+        while !self.write_buf.is_empty() {
+            let n = self.inner.write(&self.write_buf)?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.write_buf.drain(..n);
+        }
+        Ok(())
+    }
+}
+
+impl<T: Read> Read for TrailerIo<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Trailers are held back until they're complete, so a read from the socket
+        // can produce nothing to return yet
+        while self.read_pos == self.read_buf.len() {
+            let mut data = [0u8; 8192];
+            let n = self.inner.read(&mut data)?;
+            if n == 0 && self.reading.in_message() {
+                // hyper can't tell the held back trailers went missing otherwise
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            if n == 0 {
+                return Ok(0);
+            }
+            self.read_buf.clear();
+            self.read_pos = 0;
+            self.reading
+                .process(&data[..n], &mut self.read_buf, &mut self.heads, &self.slot);
+        }
+        let n = buf.len().min(self.read_buf.len() - self.read_pos);
+        buf[..n].copy_from_slice(&self.read_buf[self.read_pos..self.read_pos + n]);
+        self.read_pos += n;
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for TrailerIo<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.drain()?;
+        self.writing
+            .process(buf, &mut self.write_buf, &mut self.heads, &self.slot);
+        match self.drain() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            r => r?,
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.drain()?;
+        self.inner.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for TrailerIo<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for TrailerIo<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self.drain() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+            r => r?,
+        }
+        self.inner.shutdown()
+    }
+}
+
+type Wrap<T> = fn((T, Connected)) -> (TrailerIo<T>, Connected);
+
+/// Wraps upstream connections in `TrailerIo`, making each connection's slot
+/// available in the extensions of its responses
+pub struct TrailerConnector<C> {
+    inner: C,
+}
+
+impl<C> TrailerConnector<C> {
+    pub fn new(inner: C) -> TrailerConnector<C> {
+        TrailerConnector { inner }
+    }
+}
+
+impl<C: Connect> Connect for TrailerConnector<C> {
+    type Transport = TrailerIo<C::Transport>;
+    type Error = C::Error;
+    type Future = future::Map<C::Future, Wrap<C::Transport>>;
+
+    fn connect(&self, dst: Destination) -> Self::Future {
+        fn wrap<T>((io, connected): (T, Connected)) -> (TrailerIo<T>, Connected) {
+            let io = TrailerIo::client(io);
+            let slot = io.slot();
+            (io, connected.extra(slot))
+        }
+        self.inner.connect(dst).map(wrap as Wrap<C::Transport>)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Action, Kind, Scanner, TrailerIo, TrailerSlot};
+    use futures::{stream, task, Async, Future, Poll, Stream};
+    use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+    use hyper::service::service_fn_ok;
+    use hyper::{Body, Chunk, Request, Response};
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::VecDeque;
+    use std::io::{self, Read, Write};
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncRead, AsyncWrite};
+    use tokio::runtime::current_thread::Runtime;
+
+    fn run(
+        scanner: &mut Scanner,
+        input: &[&[u8]],
+        heads: &mut VecDeque<bool>,
+        slot: &TrailerSlot,
+    ) -> Vec<u8> {
+        let mut out = Vec::new();
+        for i in input {
+            scanner.process(i, &mut out, heads, slot);
+        }
+        out
+    }
+
+    #[test]
+    fn test_capture_trailers() {
+        let mut heads = VecDeque::new();
+        let slot = TrailerSlot::default();
+        let mut requests = Scanner::new(Kind::Request, Action::Observe);
+        let mut responses = Scanner::new(Kind::Response, Action::Capture);
+        run(
+            &mut requests,
+            &[b"GET / HTTP/1.1\r\n\r\n"],
+            &mut heads,
+            &slot,
+        );
+        let out = run(
+            &mut responses,
+            &[
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nab",
+                b"c\r\n0\r\nx-checksum: 12",
+                b"34\r\n\r\n",
+            ],
+            &mut heads,
+            &slot,
+        );
+        assert_eq!(
+            &b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n"[..],
+            &out[..]
+        );
+        assert_eq!("1234", slot.take().unwrap()["x-checksum"]);
+    }
+
+    #[test]
+    fn test_inject_trailers() {
+        let mut heads = VecDeque::new();
+        let slot = TrailerSlot::default();
+        let mut requests = Scanner::new(Kind::Request, Action::Observe);
+        let mut responses = Scanner::new(Kind::Response, Action::Inject);
+        run(
+            &mut requests,
+            &[b"HEAD / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n"],
+            &mut heads,
+            &slot,
+        );
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        slot.put(trailers);
+        let out = run(
+            &mut responses,
+            &[
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n",
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n",
+                b"0\r\n\r\n",
+            ],
+            &mut heads,
+            &slot,
+        );
+        assert!(out.ends_with(b"2\r\nok\r\n0\r\ngrpc-status: 0\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_eof_in_trailers() {
+        let cut = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n0\r\nx-checksum: 12";
+        let mut io = TrailerIo::client(io::Cursor::new(cut.to_vec()));
+        let mut out = Vec::new();
+        let err = io.read_to_end(&mut out).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+        assert!(out.ends_with(b"\r\n0\r\n"));
+    }
+
+    /// One end of a connection, reading `input` in pieces of random size and keeping
+    /// what's written.  A client end waits for its request to be written first.
+    struct Scripted {
+        input: Vec<u8>,
+        pos: usize,
+        rng: SmallRng,
+        client: bool,
+        output: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Scripted {
+        fn new(input: Vec<u8>, rng: &mut SmallRng, client: bool) -> Scripted {
+            Scripted {
+                input,
+                pos: 0,
+                rng: SmallRng::seed_from_u64(rng.gen()),
+                client,
+                output: Arc::default(),
+            }
+        }
+    }
+
+    impl Read for Scripted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.client && self.output.lock().unwrap().is_empty() {
+                task::current().notify();
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            if !self.client && self.pos == self.input.len() {
+                // Leave the server to finish its response
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let rest = self.input.len() - self.pos;
+            let n = self.rng.gen_range(1, 64).min(buf.len()).min(rest);
+            buf[..n].copy_from_slice(&self.input[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    impl Write for Scripted {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for Scripted {}
+
+    impl AsyncWrite for Scripted {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    fn random_chunks(rng: &mut SmallRng) -> Vec<Vec<u8>> {
+        (0..rng.gen_range(0, 5))
+            .map(|_| {
+                let len = rng.gen_range(1, 100);
+                (0..len).map(|_| rng.gen_range(b'a', b'z')).collect()
+            })
+            .collect()
+    }
+
+    fn random_trailers(rng: &mut SmallRng) -> HeaderMap {
+        let mut trailers = HeaderMap::new();
+        for i in 0..rng.gen_range(0, 3) {
+            let value = rng.gen_range(0, 1_000_000).to_string();
+            let name = HeaderName::from_bytes(format!("x-trailer-{}", i).as_bytes());
+            trailers.insert(name.unwrap(), HeaderValue::from_str(&value).unwrap());
+        }
+        trailers
+    }
+
+    /// The body and trailers hyper reads from `response` on a client connection
+    fn hyper_reads(response: Vec<u8>, rng: &mut SmallRng) -> Result<(Vec<u8>, HeaderMap), String> {
+        let io = TrailerIo::client(Scripted::new(response, rng, true));
+        let slot = io.slot();
+        let mut rt = Runtime::new().unwrap();
+        let read = hyper::client::conn::handshake(io).and_then(|(mut send, conn)| {
+            tokio::executor::current_thread::spawn(conn.map_err(|_| ()));
+            send.send_request(Request::get("/").body(Body::empty()).unwrap())
+                .and_then(|res| res.into_body().concat2())
+        });
+        let body = rt.block_on(read).map_err(|e| e.to_string())?;
+        Ok((body.to_vec(), slot.take().unwrap_or_default()))
+    }
+
+    #[test]
+    fn test_hyper_reads_captured_trailers() {
+        for seed in 0..200 {
+            let mut rng = SmallRng::seed_from_u64(seed);
+            let chunks = random_chunks(&mut rng);
+            let trailers = random_trailers(&mut rng);
+            let mut response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+            for c in &chunks {
+                let size = match rng.gen_range(0, 3) {
+                    0 => format!("{:x}", c.len()),
+                    1 => format!("{:X}", c.len()),
+                    _ => format!("{:x};ext=1", c.len()),
+                };
+                response.extend_from_slice(format!("{}\r\n", size).as_bytes());
+                response.extend_from_slice(c);
+                response.extend_from_slice(b"\r\n");
+            }
+            response.extend_from_slice(b"0\r\n");
+            for (k, v) in trailers.iter() {
+                response.extend_from_slice(format!("{}: ", k).as_bytes());
+                response.extend_from_slice(v.as_bytes());
+                response.extend_from_slice(b"\r\n");
+            }
+            response.extend_from_slice(b"\r\n");
+
+            if rng.gen_bool(0.3) {
+                // Cut off somewhere after the head
+                let cut = rng.gen_range(48, response.len());
+                response.truncate(cut);
+                let read = hyper_reads(response, &mut rng);
+                assert!(read.is_err(), "seed {} read a cut off response", seed);
+            } else {
+                let read = hyper_reads(response, &mut rng).unwrap();
+                assert_eq!((chunks.concat(), trailers), read, "seed {}", seed);
+            }
+        }
+    }
+
+    #[test]
+    fn test_hyper_writes_injected_trailers() {
+        for seed in 0..100 {
+            let mut rng = SmallRng::seed_from_u64(seed);
+            let chunks = random_chunks(&mut rng);
+            let trailers = random_trailers(&mut rng);
+            let request = b"GET / HTTP/1.1\r\nhost: x\r\nconnection: close\r\n\r\n".to_vec();
+            let scripted = Scripted::new(request, &mut rng, false);
+            let written = scripted.output.clone();
+            let io = TrailerIo::server(scripted);
+            let slot = io.slot();
+            let (body, sent) = (chunks.clone(), trailers.clone());
+            let service = service_fn_ok(move |_| {
+                slot.put(sent.clone());
+                let chunks = body.clone().into_iter().map(Chunk::from);
+                Response::new(Body::wrap_stream(stream::iter_ok::<_, io::Error>(chunks)))
+            });
+            let serve = hyper::server::conn::Http::new().serve_connection(io, service);
+            Runtime::new().unwrap().block_on(serve).unwrap();
+
+            // Read back through the capturing side
+            let response = written.lock().unwrap().clone();
+            let read = hyper_reads(response, &mut rng).unwrap();
+            assert_eq!((chunks.concat(), trailers), read, "seed {}", seed);
+        }
+    }
+}
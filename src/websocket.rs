@@ -0,0 +1,268 @@
+use crate::archive::{Direction, WebSocketMessage};
+use bytes::{BufMut, BytesMut};
+use failure::Error;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use sha1::{Digest, Sha1};
+use std::convert::TryFrom;
+use std::io;
+use std::time::Instant;
+use tokio::codec::{Decoder, Encoder};
+
+// see https://tools.ietf.org/html/rfc6455#section-1.3
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub const OPCODE_CLOSE: u8 = 0x8;
+
+/// Largest frame payload accepted unless configured otherwise
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Whether the request asks to switch the connection to the WebSocket protocol
+pub fn is_upgrade_request(headers: &HeaderMap) -> bool {
+    let has_token = |name: header::HeaderName, token: &str| {
+        headers.get_all(name).iter().any(|v| {
+            v.to_str()
+                .map(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+                .unwrap_or(false)
+        })
+    };
+    has_token(header::CONNECTION, "upgrade") && has_token(header::UPGRADE, "websocket")
+}
+
+/// The `Sec-WebSocket-Accept` value answering the client's `Sec-WebSocket-Key`
+pub fn accept_key(key: &[u8]) -> HeaderValue {
+    let mut digest = Sha1::new();
+    digest.input(key);
+    digest.input(ACCEPT_GUID.as_bytes());
+    let accept = base64::encode(&digest.result());
+    HeaderValue::from_str(&accept).expect("base64 is a valid header value")
+}
+
+/// A single WebSocket frame.  The payload is always stored unmasked;
+/// `mask` is applied again when the frame is encoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub mask: Option<[u8; 4]>,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn is_close(&self) -> bool {
+        self.opcode == OPCODE_CLOSE
+    }
+
+    pub fn to_message(&self, direction: Direction, start: Instant) -> WebSocketMessage {
+        let elapsed = start.elapsed();
+        let (data, encoding) = match String::from_utf8(self.payload.clone()) {
+            Ok(s) => (s, None),
+            Err(e) => (base64::encode(e.as_bytes()), Some("base64".to_string())),
+        };
+        WebSocketMessage {
+            direction,
+            time: elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis()),
+            opcode: self.opcode,
+            fin: self.fin,
+            data,
+            encoding,
+        }
+    }
+
+    /// Frames built from a recording are sent by the server, so they're never masked
+    pub fn from_message(message: &WebSocketMessage) -> Result<Frame, Error> {
+        Ok(Frame {
+            fin: message.fin,
+            opcode: message.opcode,
+            mask: None,
+            payload: message_payload(message)?,
+        })
+    }
+}
+
+pub fn message_payload(message: &WebSocketMessage) -> Result<Vec<u8>, Error> {
+    match message.encoding {
+        Some(ref e) if e == "base64" => Ok(base64::decode(&message.data)?),
+        _ => Ok(message.data.as_bytes().to_vec()),
+    }
+}
+
+fn apply_mask(mask: [u8; 4], data: &mut [u8]) {
+    for (i, b) in data.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+}
+
+pub struct FrameCodec {
+    /// Frames announcing a longer payload are refused rather than buffered
+    max_frame_size: usize,
+}
+
+impl FrameCodec {
+    pub fn new(max_frame_size: usize) -> FrameCodec {
+        FrameCodec { max_frame_size }
+    }
+}
+
+impl Default for FrameCodec {
+    fn default() -> FrameCodec {
+        FrameCodec::new(DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Frame>, io::Error> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let fin = buf[0] & 0x80 != 0;
+        let opcode = buf[0] & 0x0f;
+        let masked = buf[1] & 0x80 != 0;
+        let (len, mut offset) = match buf[1] & 0x7f {
+            126 => {
+                if buf.len() < 4 {
+                    return Ok(None);
+                }
+                ((u64::from(buf[2]) << 8) | u64::from(buf[3]), 4)
+            }
+            127 => {
+                if buf.len() < 10 {
+                    return Ok(None);
+                }
+                let len = buf[2..10]
+                    .iter()
+                    .fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+                (len, 10)
+            }
+            l => (u64::from(l), 2),
+        };
+        let mask = if masked {
+            if buf.len() < offset + 4 {
+                return Ok(None);
+            }
+            let mut m = [0u8; 4];
+            m.copy_from_slice(&buf[offset..offset + 4]);
+            offset += 4;
+            Some(m)
+        } else {
+            None
+        };
+        let len = match usize::try_from(len) {
+            Ok(len) if len <= self.max_frame_size => len,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "WebSocket frame of {} bytes is over the limit of {}",
+                        len, self.max_frame_size
+                    ),
+                ))
+            }
+        };
+        match offset.checked_add(len) {
+            Some(end) if buf.len() >= end => {}
+            _ => return Ok(None),
+        }
+        buf.advance(offset);
+        let mut payload = buf.split_to(len).to_vec();
+        if let Some(m) = mask {
+            apply_mask(m, &mut payload);
+        }
+        Ok(Some(Frame {
+            fin,
+            opcode,
+            mask,
+            payload,
+        }))
+    }
+}
+
+impl Encoder for FrameCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Frame, buf: &mut BytesMut) -> Result<(), io::Error> {
+        let len = frame.payload.len();
+        buf.reserve(len + 14);
+        let fin = if frame.fin { 0x80 } else { 0 };
+        buf.put_u8(fin | (frame.opcode & 0x0f));
+        let masked = if frame.mask.is_some() { 0x80 } else { 0 };
+        if len < 126 {
+            buf.put_u8(masked | len as u8);
+        } else if len <= 0xffff {
+            buf.put_u8(masked | 126);
+            buf.put_u16_be(len as u16);
+        } else {
+            buf.put_u8(masked | 127);
+            buf.put_u64_be(len as u64);
+        }
+        let mut payload = frame.payload;
+        if let Some(m) = frame.mask {
+            buf.put_slice(&m);
+            apply_mask(m, &mut payload);
+        }
+        buf.put_slice(&payload);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{accept_key, Frame, FrameCodec};
+    use bytes::BytesMut;
+    use std::io;
+    use tokio::codec::{Decoder, Encoder};
+
+    #[test]
+    fn test_accept_key() {
+        // example from RFC 6455, section 1.3
+        assert_eq!(
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=",
+            accept_key(b"dGhlIHNhbXBsZSBub25jZQ==")
+        );
+    }
+
+    #[test]
+    fn test_frame_round_trip() {
+        let frames = vec![
+            Frame {
+                fin: true,
+                opcode: 1,
+                mask: Some([1, 2, 3, 4]),
+                payload: b"Hello".to_vec(),
+            },
+            Frame {
+                fin: false,
+                opcode: 2,
+                mask: None,
+                payload: vec![7; 70000],
+            },
+        ];
+        let mut buf = BytesMut::new();
+        for f in &frames {
+            FrameCodec::default().encode(f.clone(), &mut buf).unwrap();
+        }
+        assert_eq!(&[0x81, 0x85, 1, 2, 3, 4], &buf[..6]);
+        for f in frames {
+            assert_eq!(Some(f), FrameCodec::default().decode(&mut buf).unwrap());
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_oversized_frame() {
+        let mut codec = FrameCodec::new(1024);
+        let mut buf = BytesMut::from(&[0x82, 126, 0x04, 0x01][..]);
+        let e = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+
+        // 2^63 bytes, which doesn't fit in memory or even a usize on some targets
+        let mut header = vec![0x82, 0xff, 0x80, 0, 0, 0, 0, 0, 0, 0];
+        header.extend_from_slice(&[1, 2, 3, 4]);
+        let mut buf = BytesMut::from(&header[..]);
+        let e = FrameCodec::default().decode(&mut buf).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+    }
+}